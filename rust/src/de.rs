@@ -1,17 +1,111 @@
-use serde::de::{self, EnumAccess, MapAccess, SeqAccess, VariantAccess, Visitor};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde::de::{self, EnumAccess, IntoDeserializer, MapAccess, SeqAccess, VariantAccess, Visitor};
 use serde::{forward_to_deserialize_any, Deserialize};
 
-use crate::error::{Error, Result};
+use crate::error::{Error, Result, Warning};
+use crate::escape::{parse_escape, unescape};
+use crate::span::Position;
+
+#[derive(Debug, Clone, Default)]
+pub struct DeserializerOptions {
+    /// Whether to record [Warning]s about suspicious keys while deserializing
+    pub collect_warnings: bool,
+    /// What to do with a `~Variant` tag that the enum doesn't have
+    pub unknown_variant_policy: UnknownVariantPolicy,
+    /// How many lists and maps deep the input may nest, to avoid overflowing
+    /// the stack on malicious input. Unlimited if `None`.
+    pub max_depth: Option<usize>,
+    /// How many bytes long a string may be once escapes are decoded, so that
+    /// huge strings can be rejected. Unlimited if `None`.
+    pub max_string_len: Option<usize>,
+    /// Whether a key that appears twice in the same map is an error. If not,
+    /// the type being deserialized decides, e.g. a HashMap keeps the last
+    /// value.
+    pub strict_duplicates: bool,
+    /// Whether strings read as byte arrays are hex, as written with
+    /// [crate::SerializerOptions::hex_bytes], rather than taken byte for byte
+    pub hex_bytes: bool,
+}
+
+/// How to handle enum variants that the type being deserialized doesn't know
+/// about, e.g. ones added in a newer version of a config format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownVariantPolicy {
+    /// Fail with serde's unknown variant error
+    #[default]
+    Error,
+    /// Skip the unknown variant's payload and use the given variant instead,
+    /// which has to be a unit variant
+    UnitFallback(&'static str),
+    /// Drop the whole element. Only works for elements of a list, since by the
+    /// time a map value is read its key has already been handed out.
+    SkipEntry,
+}
 
 pub struct Deserializer<'de> {
     // This string starts with the input data and characters are truncated off
     // the beginning as data is parsed.
     input: &'de str,
+    original: &'de str,
+    /// Offset of the last token that was started, which errors from visitors
+    /// are reported at
+    token_start: usize,
+    options: DeserializerOptions,
+    warnings: Vec<Warning>,
+    /// The fields of the struct whose keys are currently being read, if any
+    struct_fields: Option<&'static [&'static str]>,
+    /// Offset of the list element currently being read, if any
+    element_start: Option<usize>,
+    /// Whether a map key is being read. Keys can leave out the `~Type` tag
+    /// on newtype structs and unit variants.
+    reading_key: bool,
+    /// Brackets that haven't been closed yet and their offsets, innermost last
+    open_brackets: Vec<(char, usize)>,
+    /// Keys seen so far in each map being read, innermost last. Only kept if
+    /// duplicates are strict.
+    map_keys: Vec<HashSet<String>>,
+    implicit_map: ImplicitMap,
+}
+
+/// Whether the whole document is a map without braces around it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ImplicitMap {
+    No,
+    /// The map starts at the next value that's read
+    Pending,
+    /// Reading the map, which ends at the end of the input
+    Open,
 }
 
 impl<'de> Deserializer<'de> {
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(input: &'de str) -> Self {
-        Deserializer { input }
+        Deserializer::with_options(input, DeserializerOptions::default())
+    }
+
+    pub fn with_options(input: &'de str, options: DeserializerOptions) -> Self {
+        Deserializer {
+            input,
+            original: input,
+            token_start: 0,
+            options,
+            warnings: Vec::new(),
+            struct_fields: None,
+            element_start: None,
+            reading_key: false,
+            open_brackets: Vec::new(),
+            map_keys: Vec::new(),
+            implicit_map: ImplicitMap::No,
+        }
+    }
+
+    /// Warnings collected so far (only if enabled in the options)
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
     }
 }
 
@@ -20,15 +114,114 @@ where
     T: Deserialize<'a>,
 {
     let mut deserializer = Deserializer::from_str(s);
-    let t = T::deserialize(&mut deserializer)?;
+    deserialize_all(&mut deserializer)
+}
+
+/// Like [from_str], but a document with several values at the top level, e.g.
+///
+/// ```text
+/// name foo
+/// port 8080
+/// ```
+///
+/// is read as a map without the braces. Documents that start with `[` or `{`
+/// or only have a single value are read the same as with [from_str], so
+/// `true` is a bool but `true false` is a map with one entry.
+pub fn from_str_document<'a, T>(s: &'a str) -> Result<T>
+where
+    T: Deserialize<'a>,
+{
+    from_str_document_with_options(s, DeserializerOptions::default()).map(|(t, _)| t)
+}
+
+/// Like [from_str_document], but with the given options, also returning any
+/// warnings collected
+pub fn from_str_document_with_options<'a, T>(
+    s: &'a str,
+    options: DeserializerOptions,
+) -> Result<(T, Vec<Warning>)>
+where
+    T: Deserialize<'a>,
+{
+    let implicit_map = is_implicit_map(s, &options);
+    let mut deserializer = Deserializer::with_options(s, options);
+    if implicit_map {
+        deserializer.implicit_map = ImplicitMap::Pending;
+    }
+    let t = deserialize_all(&mut deserializer)?;
+    Ok((t, deserializer.warnings))
+}
+
+/// Whether a document has more than one value at the top level. Errors are
+/// left for the real deserializer to find. The options are used so that the
+/// first value is skipped within the same limits.
+fn is_implicit_map(s: &str, options: &DeserializerOptions) -> bool {
+    let mut probe = Deserializer::with_options(s, options.clone());
+    if probe.trim_ignored().is_err()
+        || probe.input.is_empty()
+        || probe.input.starts_with(['[', '{'])
+    {
+        return false;
+    }
+    probe.skip_value().is_ok() && probe.trim_ignored().is_ok() && !probe.input.is_empty()
+}
+
+/// Read the whole input from `reader` and deserialize it
+pub fn from_reader<R, T>(mut reader: R) -> Result<T>
+where
+    R: io::Read,
+    T: DeserializeOwned,
+{
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes).map_err(Error::Io)?;
+    let s = String::from_utf8(bytes).map_err(|e| Error::InvalidUtf8 {
+        offset: e.utf8_error().valid_up_to(),
+    })?;
+    from_str(&s)
+}
+
+/// Like [from_str], but also returns warnings about keys that look like
+/// mistakes, e.g. `" port"` when the struct has a `port` field
+pub fn from_str_with_warnings<'a, T>(s: &'a str) -> Result<(T, Vec<Warning>)>
+where
+    T: Deserialize<'a>,
+{
+    let options = DeserializerOptions {
+        collect_warnings: true,
+        ..Default::default()
+    };
+    from_str_with_options(s, options)
+}
+
+/// Deserialize with the given options, also returning any warnings collected
+pub fn from_str_with_options<'a, T>(
+    s: &'a str,
+    options: DeserializerOptions,
+) -> Result<(T, Vec<Warning>)>
+where
+    T: Deserialize<'a>,
+{
+    let mut deserializer = Deserializer::with_options(s, options);
+    let t = deserialize_all(&mut deserializer)?;
+    Ok((t, deserializer.warnings))
+}
+
+fn deserialize_all<'a, T>(deserializer: &mut Deserializer<'a>) -> Result<T>
+where
+    T: Deserialize<'a>,
+{
+    let t = T::deserialize(&mut *deserializer).map_err(|e| deserializer.fix_position(e))?;
+    deserializer.trim_ignored()?;
     if deserializer.input.is_empty() {
         Ok(t)
+    } else if deserializer.input.starts_with([']', '}']) {
+        Err(deserializer.bracket_error())
     } else {
         Err(Error::TrailingCharacters(deserializer.input.to_string()))
     }
 }
 
-const SPECIAL_CHARS: [char; 4] = ['{', '}', '[', ']'];
+const SPECIAL_CHARS: [char; 5] = ['{', '}', '[', ']', ','];
 
 impl<'de> Deserializer<'de> {
     fn peek(&mut self) -> Result<char> {
@@ -56,14 +249,435 @@ impl<'de> Deserializer<'de> {
                     .take_while(|c| c.is_whitespace())
                     .collect();
                 self.input = &self.input[ws.len()..];
+            } else if self.input.starts_with("#[") {
+                self.skip_multiline_comment()?;
             } else if c == '#' {
+                // Single-line comment, the newline itself is left as whitespace
+                let end = self.input.find('\n').unwrap_or(self.input.len());
+                self.input = &self.input[end..];
             } else {
                 break;
             }
         }
+        self.token_start = self.offset();
+        Ok(())
+    }
+
+    /// Consume a `[` or `{`, remembering where it was
+    fn open_bracket(&mut self) -> Result<()> {
+        let offset = self.offset();
+        if let Some(max_depth) = self.options.max_depth {
+            if self.open_brackets.len() >= max_depth {
+                return Err(Error::MaxDepthExceeded {
+                    position: self.position(offset),
+                    max_depth,
+                });
+            }
+        }
+        let bracket = self.next()?;
+        self.open_brackets.push((bracket, offset));
+        Ok(())
+    }
+
+    /// Consume the `]` or `}` that closes the innermost open bracket
+    fn close_bracket(&mut self) -> Result<()> {
+        self.next()?;
+        self.open_brackets.pop();
+        Ok(())
+    }
+
+    /// Error for a `]` or `}` that isn't where the innermost open bracket
+    /// expects to be closed
+    fn bracket_error(&self) -> Error {
+        let position = self.position(self.offset());
+        let found = self.input.chars().next().unwrap_or_default();
+        match self.open_brackets.last() {
+            Some(&(open, offset)) => Error::MismatchedDelimiter {
+                position,
+                found,
+                open_position: self.position(offset),
+                expected: closing_bracket(open),
+            },
+            None => Error::UnmatchedEndDelimiter {
+                position,
+                delimiter: found,
+            },
+        }
+    }
+
+    /// Deserialize a fixed-size list. The visitor stops asking for elements
+    /// once it has as many as it needs, so unlike with other lists, the `]`
+    /// may still be left over afterwards.
+    fn visit_tuple<V>(&mut self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let depth = self.open_brackets.len();
+        let val = de::Deserializer::deserialize_seq(&mut *self, visitor)?;
+        if self.open_brackets.len() > depth {
+            self.trim_ignored()?;
+            if self.peek()? != ']' {
+                return Err(self.bracket_error());
+            }
+            self.close_bracket()?;
+        }
+        Ok(val)
+    }
+
+    /// Start the braceless map around the document, if that's what comes next
+    fn start_implicit_map(&mut self) -> bool {
+        if self.implicit_map == ImplicitMap::Pending {
+            self.implicit_map = ImplicitMap::Open;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Skip the comma after an entry in a list or map, if there is one
+    fn skip_separator(&mut self) -> Result<()> {
+        self.trim_ignored()?;
+        if self.input.starts_with(',') {
+            self.next()?;
+        }
         Ok(())
     }
 
+    /// Skip a `#[ ... #]` comment, which may contain other multiline comments
+    fn skip_multiline_comment(&mut self) -> Result<()> {
+        let start = self.offset();
+        let mut depth = 0;
+        loop {
+            if self.input.starts_with("#[") {
+                depth += 1;
+                self.input = &self.input[2..];
+            } else if self.input.starts_with("#]") {
+                depth -= 1;
+                self.input = &self.input[2..];
+                if depth == 0 {
+                    return Ok(());
+                }
+            } else if self.input.is_empty() {
+                return Err(Error::UnterminatedComment {
+                    position: self.position(start),
+                });
+            } else {
+                self.next()?;
+            }
+        }
+    }
+
+    /// Byte offset of the remaining input within the original input
+    fn offset(&self) -> usize {
+        self.original.len() - self.input.len()
+    }
+
+    fn position(&self, offset: usize) -> Position {
+        Position::new(self.original, offset)
+    }
+
+    /// Error for when the next token isn't what's expected there
+    fn unexpected(&self, expected: &str) -> Error {
+        let found = match self.input.chars().next() {
+            None => "end of input".to_string(),
+            Some(c) if Self::ends_word(c) => format!("`{}`", c),
+            Some(_) => {
+                let word: String = self
+                    .input
+                    .chars()
+                    .take_while(|&c| !Self::ends_word(c))
+                    .collect();
+                format!("`{}`", word)
+            }
+        };
+        Error::Unexpected {
+            expected: expected.to_string(),
+            found,
+            position: Some(self.position(self.offset())),
+        }
+    }
+
+    /// Error for when the next char can't go here, or [Error::Eof] if there
+    /// isn't one
+    fn unexpected_token(&self, expected: &'static str) -> Error {
+        match self.input.chars().next() {
+            Some(got) => Error::UnexpectedToken {
+                position: self.position(self.offset()),
+                got,
+                expected,
+            },
+            None => Error::Eof,
+        }
+    }
+
+    fn message(&self, offset: usize, msg: String) -> Error {
+        Error::Message {
+            msg,
+            position: Some(self.position(offset)),
+        }
+    }
+
+    /// Give errors that came from a visitor the position of the token that
+    /// was being read
+    fn fix_position(&self, err: Error) -> Error {
+        let position = Some(self.position(self.token_start));
+        match err {
+            Error::Unexpected {
+                expected,
+                found,
+                position: None,
+            } => Error::Unexpected {
+                expected,
+                found,
+                position,
+            },
+            Error::Message {
+                msg,
+                position: None,
+            } => Error::Message { msg, position },
+            Error::Eof => match self.open_brackets.last() {
+                Some(&(delimiter, offset)) => Error::UnclosedDelimiter {
+                    open_position: self.position(offset),
+                    delimiter,
+                },
+                None => Error::Eof,
+            },
+            err => err,
+        }
+    }
+
+    /// Warn about a struct key that doesn't match any field but looks like it
+    /// was meant to. Must be called right before the key is parsed.
+    fn check_key(&mut self, fields: &'static [&'static str]) {
+        let quoted = matches!(self.input.chars().next(), Some('"' | '\''));
+        let mut probe = Deserializer::from_str(self.input);
+        let key = match probe.parse_str() {
+            Ok(key) => key,
+            Err(_) => return,
+        };
+        if fields.contains(&key.as_ref()) {
+            return;
+        }
+
+        let trimmed = key.trim();
+        let message = if quoted && fields.contains(&trimmed) {
+            format!(
+                "key \"{}\" has whitespace inside the quotes and doesn't match field `{}`",
+                key, trimmed
+            )
+        } else if let Some(field) = fields
+            .iter()
+            .find(|f| f.to_lowercase() == key.to_lowercase())
+        {
+            format!(
+                "key `{}` doesn't match field `{}` because keys are case-sensitive",
+                key, field
+            )
+        } else if let Some(field) = closest_field(&key, fields) {
+            format!("unknown key `{}`, did you mean `{}`?", key, field)
+        } else {
+            return;
+        };
+        self.warn(self.offset(), message);
+    }
+
+    /// Fail if the key that was just read, starting at `start`, is already in
+    /// the map being read. Keys are compared by their text, with strings
+    /// unquoted and unescaped so that `a` and `"a"` are the same key.
+    fn check_duplicate(&mut self, start: usize) -> Result<()> {
+        let text = self.original[start..self.offset()].trim_end();
+        let mut probe = Deserializer::from_str(text);
+        let key = match probe.parse_str() {
+            Ok(key) if probe.input.is_empty() => key.into_owned(),
+            _ => text.to_string(),
+        };
+        let new = self
+            .map_keys
+            .last_mut()
+            .is_none_or(|keys| keys.insert(key.clone()));
+        if new {
+            Ok(())
+        } else {
+            Err(Error::DuplicateKey {
+                position: self.position(start),
+                key,
+            })
+        }
+    }
+
+    fn warn(&mut self, offset: usize, message: String) {
+        if self.options.collect_warnings {
+            let position = self.position(offset);
+            self.warnings.push(Warning { position, message });
+        }
+    }
+
+    /// Skip over a whole value, including any type tag in front of it
+    fn skip_value(&mut self) -> Result<()> {
+        self.trim_ignored()?;
+        // Tags don't nest, so skip them in a loop rather than recursing
+        while self.peek()? == '~' {
+            self.next()?;
+            self.parse_str()?;
+            self.trim_ignored()?;
+        }
+        match self.peek()? {
+            open @ ('[' | '{') => {
+                let close = closing_bracket(open);
+                self.open_bracket()?;
+                loop {
+                    self.trim_ignored()?;
+                    if self.peek()? == close {
+                        self.close_bracket()?;
+                        return Ok(());
+                    }
+                    self.skip_value()?;
+                    self.skip_separator()?;
+                }
+            }
+            _ => self.parse_str().map(|_| ()),
+        }
+    }
+
+    /// Get ready to read the next element of a list. Returns false (after
+    /// consuming the `]`) if the list has ended.
+    fn start_element(&mut self) -> Result<bool> {
+        self.trim_ignored()?;
+        if self.peek()? == ']' {
+            self.close_bracket()?;
+            Ok(false)
+        } else {
+            self.element_start = Some(self.offset());
+            Ok(true)
+        }
+    }
+
+    /// Deal with a `~Variant` tag that isn't one of `variants`, according to the
+    /// unknown variant policy. `start` is the offset of the `~`, which has
+    /// already been consumed.
+    fn handle_unknown_variant<V>(
+        &mut self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        mut start: usize,
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        loop {
+            let mut probe = Deserializer::from_str(self.input);
+            let variant = probe.parse_str()?;
+            if variants.contains(&variant.as_ref()) {
+                return visitor.visit_enum(self);
+            }
+
+            match self.options.unknown_variant_policy {
+                UnknownVariantPolicy::Error => return visitor.visit_enum(self),
+                UnknownVariantPolicy::UnitFallback(fallback) => {
+                    let not_unit = self.message(
+                        start,
+                        format!("Fallback `{}` is not a unit variant of {}", fallback, name),
+                    );
+                    if !variants.contains(&fallback) {
+                        return Err(not_unit);
+                    }
+                    self.parse_str()?;
+                    self.skip_value()?;
+                    self.warn(
+                        start,
+                        format!(
+                            "unknown variant `{}` of {} replaced with `{}`",
+                            variant, name, fallback
+                        ),
+                    );
+                    // Anything but a unit variant fails to read from just a name
+                    return visitor
+                        .visit_enum(IntoDeserializer::<Error>::into_deserializer(fallback))
+                        .map_err(|_| not_unit);
+                }
+                UnknownVariantPolicy::SkipEntry => {
+                    if self.element_start != Some(start) {
+                        return Err(self.message(
+                            start,
+                            format!(
+                                "Unknown variant `{}` of {} can only be skipped in a list",
+                                variant, name
+                            ),
+                        ));
+                    }
+                    self.parse_str()?;
+                    self.skip_value()?;
+                    self.skip_separator()?;
+                    self.warn(
+                        start,
+                        format!(
+                            "skipped element with unknown variant `{}` of {}",
+                            variant, name
+                        ),
+                    );
+                    if !self.start_element()? {
+                        // There's no element to return, so this unwinds out of
+                        // the visitor, and next_element_seed sees that the
+                        // list was closed
+                        return Err(self.message(
+                            self.offset(),
+                            "No elements left after skipping unknown variants".to_string(),
+                        ));
+                    }
+                    start = self.offset();
+                    if self.next()? != '~' {
+                        return Err(Error::ExpectedType {
+                            position: self.position(start),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Read a map after its `{`, with `fields` being the struct fields it should
+    /// contain if it's a struct
+    fn visit_map_with_fields<V>(
+        &mut self,
+        fields: Option<&'static [&'static str]>,
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let outer = std::mem::replace(&mut self.struct_fields, fields);
+        if self.options.strict_duplicates {
+            self.map_keys.push(HashSet::new());
+        }
+        let val = visitor.visit_map(&mut *self);
+        if self.options.strict_duplicates {
+            self.map_keys.pop();
+        }
+        self.struct_fields = outer;
+        val
+    }
+
+    /// Skip a `~Type` tag if there is one. If `name` is given, the tag has to
+    /// match it.
+    fn parse_optional_tag(&mut self, name: Option<&str>) -> Result<()> {
+        self.trim_ignored()?;
+        if self.peek()? != '~' {
+            return Ok(());
+        }
+        let start = self.offset();
+        self.next()?;
+        let tag = self.parse_str()?;
+        match name {
+            Some(name) if tag != name => Err(Error::Unexpected {
+                expected: format!("`~{}`", name),
+                found: format!("`~{}`", tag),
+                position: Some(self.position(start)),
+            }),
+            _ => self.trim_ignored(),
+        }
+    }
+
     fn parse_keyword(&mut self, keyword: &str) -> Result<bool> {
         if !self.input.starts_with(keyword) {
             Ok(false)
@@ -78,77 +692,252 @@ impl<'de> Deserializer<'de> {
         }
     }
 
-    fn parse_str(&mut self) -> Result<String> {
+    /// Find where the string at the start of the input ends, which is at the
+    /// first char `end` is true for. If `escapes` is set, escaped chars don't
+    /// end the string. Scanning stops as soon as the string turns out to be
+    /// longer than [DeserializerOptions::max_string_len] once decoded, so
+    /// huge strings aren't read in full. `start` is where errors point.
+    fn scan_str(&self, start: usize, escapes: bool, end: impl Fn(char) -> bool) -> Result<usize> {
+        let max_len = self.options.max_string_len.unwrap_or(usize::MAX);
+        let mut len = 0;
+        let mut decoded_len = 0;
+        while let Some(c) = self.input[len..].chars().next() {
+            if end(c) {
+                break;
+            }
+            len += c.len_utf8();
+            if escapes && c == '\\' {
+                // Invalid escapes are reported when unescaping, so here the
+                // char after the backslash is just skipped
+                let (decoded, escape_len) = match parse_escape(&self.input[len..]) {
+                    Some((c, escape_len)) => (c.len_utf8(), escape_len),
+                    None => (
+                        1,
+                        self.input[len..].chars().next().map_or(0, char::len_utf8),
+                    ),
+                };
+                decoded_len += decoded;
+                len += escape_len;
+            } else {
+                decoded_len += c.len_utf8();
+            }
+            if decoded_len > max_len {
+                return Err(Error::StringTooLong {
+                    position: self.position(start),
+                    max_len,
+                });
+            }
+        }
+        Ok(len)
+    }
+
+    /// Read a string. Strings without escape sequences are borrowed from the
+    /// input.
+    fn parse_str(&mut self) -> Result<Cow<'de, str>> {
+        let str_start = self.offset();
         match self.peek()? {
             q @ ('"' | '\'') => {
                 // Normal quoted strings
                 // todo allow raw strings with r#""#
                 self.next()?;
-                let mut res = String::new();
-                while !self.input.is_empty() {
-                    let c = self.next()?;
-                    if c == q {
-                        break;
-                    } else if c == '\\' {
-                        res.push(self.next()?);
-                    } else {
-                        res.push(c);
-                    }
+                let start = self.offset();
+                let len = self.scan_str(str_start, true, |c| c == q)?;
+                let contents = &self.input[..len];
+                self.input = &self.input[len..];
+                if !self.input.is_empty() {
+                    self.next()?;
                 }
-                Ok(res)
+                if !contents.contains('\\') {
+                    return Ok(Cow::Borrowed(contents));
+                }
+                unescape(contents)
+                    .map(Cow::Owned)
+                    .map_err(|e| Error::InvalidEscape {
+                        position: self.position(start + e.offset),
+                    })
             }
             '`' => {
                 // Strings that extend to the end of the line
-                let str: String = self.input.chars().take_while(|&c| c != '\n').collect();
-                if str.is_empty() {
-                    Err(Error::Message("Expected a string, got nothing".to_string()))
-                } else {
-                    self.input = &self.input[str.len()..];
-                    Ok(str)
-                }
+                self.next()?;
+                let len = self.scan_str(str_start, false, |c| c == '\n')?;
+                let str = &self.input[..len];
+                self.input = &self.input[len..];
+                Ok(Cow::Borrowed(str))
             }
             _ => {
                 // Bare strings (single words)
-                let word: String = self
-                    .input
-                    .chars()
-                    .take_while(|&c| !Self::ends_word(c))
-                    .collect();
-                if word.is_empty() {
-                    Err(Error::Message(
-                        "Expected a word, got whitespace".to_string(),
-                    ))
+                if self.input.starts_with([']', '}']) {
+                    return Err(self.bracket_error());
+                }
+                let len = self.scan_str(str_start, false, Self::ends_word)?;
+                if len == 0 {
+                    Err(self.unexpected_token("a word"))
                 } else {
-                    self.input = &self.input[word.len()..];
-                    Ok(word)
+                    let word = &self.input[..len];
+                    self.input = &self.input[len..];
+                    Ok(Cow::Borrowed(word))
+                }
+            }
+        }
+    }
+
+    /// Parse a number if the next word is one. Integers become the smallest of
+    /// [Number::Int], [Number::UInt], [Number::Int128] and [Number::UInt128]
+    /// that fits, while anything with a decimal point or exponent becomes a
+    /// [Number::Float]. Digits can be
+    /// separated by single underscores, e.g. `1_000`.
+    fn parse_num(&mut self) -> Result<Option<Number>> {
+        if let Some(num) = self.parse_radix_int()? {
+            return Ok(Some(num));
+        }
+
+        let bytes = self.input.as_bytes();
+        let digits_from = |start: usize| {
+            let digits = &bytes[start.min(bytes.len())..];
+            let mut len = 0;
+            while let Some(&b) = digits.get(len) {
+                let separator =
+                    b == b'_' && len > 0 && digits.get(len + 1).is_some_and(u8::is_ascii_digit);
+                if !b.is_ascii_digit() && !separator {
+                    break;
                 }
+                len += 1;
+            }
+            len
+        };
+
+        let mut len = usize::from(matches!(bytes.first(), Some(b'-' | b'+')));
+        let int_digits = digits_from(len);
+        if int_digits == 0 {
+            return Ok(None);
+        }
+        len += int_digits;
+
+        let mut is_float = false;
+        if bytes.get(len) == Some(&b'.') {
+            let frac_digits = digits_from(len + 1);
+            if frac_digits > 0 {
+                len += 1 + frac_digits;
+                is_float = true;
+            }
+        }
+        if matches!(bytes.get(len), Some(b'e' | b'E')) {
+            let mut exp_len = 1;
+            if matches!(bytes.get(len + 1), Some(b'-' | b'+')) {
+                exp_len += 1;
+            }
+            let exp_digits = digits_from(len + exp_len);
+            if exp_digits > 0 {
+                len += exp_len + exp_digits;
+                is_float = true;
+            }
+        }
+
+        if let Some(c) = self.input[len..].chars().next() {
+            if !Self::ends_word(c) {
+                return Ok(None);
             }
         }
+
+        let text = self.input[..len].replace('_', "");
+        let out_of_range = || Error::NumberOutOfRange {
+            position: self.position(self.offset()),
+        };
+        let num = if is_float {
+            match text.parse::<f64>() {
+                Ok(f) if f.is_finite() => Number::Float(f),
+                _ => return Err(out_of_range()),
+            }
+        } else if let Ok(i) = text.parse::<i128>() {
+            Number::int(i)
+        } else if let Ok(u) = text.parse::<u128>() {
+            Number::UInt128(u)
+        } else {
+            return Err(out_of_range());
+        };
+        self.input = &self.input[len..];
+        Ok(Some(num))
     }
 
-    fn parse_num(&mut self) -> Result<Option<String>> {
-        // todo handle floats
-        let num: String = self.input.chars().take_while(|c| c.is_digit(10)).collect();
-        if !num.is_empty()
-            && (self.input.is_empty()
-                || Self::ends_word(self.input.chars().nth(num.len()).unwrap()))
+    /// Parse an integer written in hex (`0xFF`), binary (`0b1010`) or octal
+    /// (`0o755`), if the next word is one
+    fn parse_radix_int(&mut self) -> Result<Option<Number>> {
+        let negative = self.input.starts_with('-');
+        let sign_len = usize::from(negative || self.input.starts_with('+'));
+        let radix = match self.input.get(sign_len..sign_len + 2) {
+            Some("0x" | "0X") => 16,
+            Some("0b") => 2,
+            Some("0o") => 8,
+            _ => return Ok(None),
+        };
+        let len = self.input.find(Self::ends_word).unwrap_or(self.input.len());
+        let digits = &self.input[sign_len + 2..len];
+        if digits.is_empty()
+            || digits.starts_with('_')
+            || digits.ends_with('_')
+            || digits.contains("__")
+            || !digits.chars().all(|c| c == '_' || c.is_digit(radix))
         {
-            self.input = &self.input[num.len()..];
-            Ok(Some(num))
+            return Ok(None);
+        }
+
+        let out_of_range = || Error::NumberOutOfRange {
+            position: self.position(self.offset()),
+        };
+        let magnitude =
+            u128::from_str_radix(&digits.replace('_', ""), radix).map_err(|_| out_of_range())?;
+        let num = if negative {
+            Number::int(
+                0i128
+                    .checked_sub_unsigned(magnitude)
+                    .ok_or_else(out_of_range)?,
+            )
         } else {
-            Ok(None)
+            i128::try_from(magnitude).map_or(Number::UInt128(magnitude), Number::int)
+        };
+        self.input = &self.input[len..];
+        Ok(Some(num))
+    }
+}
+
+enum Number {
+    Int(i64),
+    UInt(u64),
+    Int128(i128),
+    UInt128(u128),
+    Float(f64),
+}
+
+impl Number {
+    /// The smallest integer variant that fits `i`, other than [Number::UInt128]
+    fn int(i: i128) -> Number {
+        if let Ok(i) = i64::try_from(i) {
+            Number::Int(i)
+        } else if let Ok(u) = u64::try_from(i) {
+            Number::UInt(u)
+        } else {
+            Number::Int128(i)
         }
     }
 }
 
-impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
     type Error = Error;
 
     fn deserialize_any<V>(self, visitor: V) -> std::result::Result<V::Value, Self::Error>
     where
         V: Visitor<'de>,
     {
+        if self.start_implicit_map() {
+            return self.visit_map_with_fields(None, visitor);
+        }
         self.trim_ignored()?;
+        // Without a type to go by, tags can only be skipped. This is enough for
+        // structs and newtypes that serde buffers, e.g. for flattened fields,
+        // but not for enums.
+        while self.input.starts_with('~') {
+            self.parse_optional_tag(None)?;
+        }
         if self.input.is_empty() {
             Err(Error::Eof)
         } else {
@@ -161,101 +950,273 @@ impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
             } else if self.parse_keyword("null")? {
                 visitor.visit_unit()
             } else if c == '[' {
-                self.next()?;
+                self.open_bracket()?;
                 visitor.visit_seq(self)
             } else if c == '{' {
-                self.next()?;
-                visitor.visit_map(self)
+                self.open_bracket()?;
+                self.visit_map_with_fields(None, visitor)
             } else {
                 match self.parse_num()? {
-                    Some(num) => visitor.visit_i32(num.parse().unwrap()),
-                    None => visitor.visit_string(self.parse_str()?),
+                    Some(Number::Int(i)) => visitor.visit_i64(i),
+                    Some(Number::UInt(u)) => visitor.visit_u64(u),
+                    Some(Number::Int128(i)) => visitor.visit_i128(i),
+                    Some(Number::UInt128(u)) => visitor.visit_u128(u),
+                    Some(Number::Float(f)) => visitor.visit_f64(f),
+                    None => visit_str(self.parse_str()?, visitor),
                 }
             }
         }
     }
 
     forward_to_deserialize_any! {
-        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char string
-        bytes byte_buf option unit unit_struct seq map
-        struct tuple_struct ignored_any
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64
+        unit seq
     }
 
-    fn deserialize_tuple<V>(self, _len: usize, visitor: V) -> Result<V::Value>
+    /// Unit structs are written as `~Name {}`, but `null` works too
+    fn deserialize_unit_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        let val = self.deserialize_seq(visitor)?;
-        if self.next()? != ']' {
-            Err(Error::Message("Expected ']'".to_string()))
+        self.parse_optional_tag(Some(name))?;
+        if self.peek()? == '{' {
+            self.open_bracket()?;
+            self.trim_ignored()?;
+            if self.peek()? != '}' {
+                return Err(self.bracket_error());
+            }
+            self.close_bracket()?;
+            visitor.visit_unit()
+        } else if self.parse_keyword("null")? {
+            visitor.visit_unit()
         } else {
-            Ok(val)
+            Err(self.unexpected("`{}` or `null`"))
         }
     }
 
-    fn deserialize_newtype_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value>
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        self.trim_ignored()?;
-        if self.next()? != '~' {
-            Err(Error::ExpectedType)
-        } else {
-            visitor.visit_newtype_struct(self)
+        if self.start_implicit_map() {
+            return self.visit_map_with_fields(None, visitor);
         }
+        self.parse_optional_tag(None)?;
+        self.deserialize_any(visitor)
     }
 
-    fn deserialize_enum<V>(
+    fn deserialize_struct<V>(
         self,
-        _name: &'static str,
-        _variants: &'static [&'static str],
+        name: &'static str,
+        fields: &'static [&'static str],
         visitor: V,
-    ) -> std::result::Result<V::Value, Self::Error>
+    ) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        self.trim_ignored()?;
-        if self.next()? != '~' {
-            Err(Error::ExpectedType)
+        if self.start_implicit_map() {
+            return self.visit_map_with_fields(Some(fields), visitor);
+        }
+        // Struct variants have already had their tag read
+        self.parse_optional_tag(Some(name).filter(|name| !name.is_empty()))?;
+        if self.peek()? == '{' {
+            self.open_bracket()?;
+            self.visit_map_with_fields(Some(fields), visitor)
         } else {
-            visitor.visit_enum(self)
+            self.deserialize_any(visitor)
         }
     }
 
-    fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value>
+    fn deserialize_tuple<V>(self, _len: usize, visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        self.trim_ignored()?;
-        self.deserialize_str(visitor)
+        self.visit_tuple(visitor)
     }
 
-    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value>
+    fn deserialize_tuple_struct<V>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        visitor.visit_string(self.parse_str()?)
+        self.visit_tuple(visitor)
     }
-}
-
-impl<'de, 'a> SeqAccess<'de> for &'a mut Deserializer<'de> {
-    type Error = Error;
 
-    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value>
     where
-        T: de::DeserializeSeed<'de>,
+        V: Visitor<'de>,
     {
         self.trim_ignored()?;
-        if self.peek()? == ']' {
-            self.next()?;
-            Ok(None)
-        } else {
-            seed.deserialize(&mut **self).map(Some)
+        if self.parse_keyword("null")? {
+            return visitor.visit_none();
+        }
+        if self.peek()? == '~' {
+            let mut probe = Deserializer::from_str(&self.input[1..]);
+            match probe.parse_str()?.as_ref() {
+                "None" => {
+                    self.input = probe.input;
+                    self.trim_ignored()?;
+                    if !self.parse_keyword("null")? {
+                        return Err(self.unexpected("`null` after `~None`"));
+                    }
+                    return visitor.visit_none();
+                }
+                "Some" => self.input = probe.input,
+                // The tag belongs to the value inside
+                _ => {}
+            }
         }
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_char<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        // Read chars as strings, so that digits aren't read as numbers
+        self.trim_ignored()?;
+        visit_str(self.parse_str()?, visitor)
+    }
+
+    fn deserialize_newtype_struct<V>(self, _name: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.trim_ignored()?;
+        if self.peek()? == '~' {
+            self.next()?;
+            self.parse_str()?;
+            visitor.visit_newtype_struct(self)
+        } else if self.reading_key {
+            visitor.visit_newtype_struct(self)
+        } else {
+            Err(Error::ExpectedType {
+                position: self.position(self.offset()),
+            })
+        }
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> std::result::Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.trim_ignored()?;
+        let start = self.offset();
+        if self.peek()? == '~' {
+            self.next()?;
+            self.handle_unknown_variant(name, variants, start, visitor)
+        } else if self.reading_key {
+            // A bare word for a unit variant
+            let variant = self.parse_str()?;
+            visitor.visit_enum(variant.into_deserializer())
+        } else {
+            Err(Error::ExpectedType {
+                position: self.position(start),
+            })
+        }
+    }
+
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.skip_value()?;
+        visitor.visit_unit()
+    }
+
+    fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.trim_ignored()?;
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.trim_ignored()?;
+        // Say what was found instead of just complaining about the bracket,
+        // e.g. for lists used as string keys
+        match self.peek()? {
+            '[' => Err(de::Error::invalid_type(de::Unexpected::Seq, &visitor)),
+            '{' => Err(de::Error::invalid_type(de::Unexpected::Map, &visitor)),
+            _ => visit_str(self.parse_str()?, visitor),
+        }
+    }
+
+    fn deserialize_string<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_str(visitor)
+    }
+
+    /// Bytes can be a list of numbers or a string
+    fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.trim_ignored()?;
+        if self.peek()? == '[' {
+            let bytes = Vec::<u8>::deserialize(&mut *self)?;
+            return visitor.visit_byte_buf(bytes);
+        }
+        let start = self.offset();
+        let s = self.parse_str()?;
+        if self.options.hex_bytes {
+            let bytes = decode_hex(&s)
+                .ok_or_else(|| self.message(start, format!("Invalid hex string {:?}", s)))?;
+            return visitor.visit_byte_buf(bytes);
+        }
+        match s {
+            Cow::Borrowed(s) => visitor.visit_borrowed_bytes(s.as_bytes()),
+            Cow::Owned(s) => visitor.visit_byte_buf(s.into_bytes()),
+        }
+    }
+
+    fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_bytes(visitor)
+    }
+}
+
+impl<'de> SeqAccess<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        if !self.start_element()? {
+            return Ok(None);
+        }
+        let depth = self.open_brackets.len();
+        let elem = seed.deserialize(&mut **self);
+        // Skipping unknown variants can run into the end of the list. Whatever
+        // the element's visitor made of that, the list is over.
+        if self.open_brackets.len() < depth {
+            return Ok(None);
+        }
+        let elem = elem?;
+        self.skip_separator()?;
+        Ok(Some(elem))
     }
 }
 
-impl<'de, 'a> MapAccess<'de> for &'a mut Deserializer<'de> {
+impl<'de> MapAccess<'de> for &mut Deserializer<'de> {
     type Error = Error;
 
     fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
@@ -263,11 +1224,27 @@ impl<'de, 'a> MapAccess<'de> for &'a mut Deserializer<'de> {
         K: de::DeserializeSeed<'de>,
     {
         self.trim_ignored()?;
-        if self.peek()? == '}' {
-            self.next()?;
+        let implicit_map = self.implicit_map == ImplicitMap::Open && self.open_brackets.is_empty();
+        if implicit_map && self.input.is_empty() {
+            Ok(None)
+        } else if !implicit_map && self.peek()? == '}' {
+            self.close_bracket()?;
             Ok(None)
         } else {
-            seed.deserialize(&mut **self).map(Some)
+            if self.options.collect_warnings {
+                if let Some(fields) = self.struct_fields {
+                    self.check_key(fields);
+                }
+            }
+            let start = self.offset();
+            let outer = std::mem::replace(&mut self.reading_key, true);
+            let key = seed.deserialize(&mut **self);
+            self.reading_key = outer;
+            let key = key?;
+            if self.options.strict_duplicates {
+                self.check_duplicate(start)?;
+            }
+            Ok(Some(key))
         }
     }
 
@@ -277,14 +1254,19 @@ impl<'de, 'a> MapAccess<'de> for &'a mut Deserializer<'de> {
     {
         self.trim_ignored()?;
         if self.peek()? == '}' {
-            return Err(Error::Message("No value given".to_string()));
+            Err(self.unexpected_token("a value"))
         } else {
-            seed.deserialize(&mut **self)
+            let outer = std::mem::replace(&mut self.reading_key, false);
+            let val = seed.deserialize(&mut **self);
+            self.reading_key = outer;
+            let val = val?;
+            self.skip_separator()?;
+            Ok(val)
         }
     }
 }
 
-impl<'de, 'a> EnumAccess<'de> for &'a mut Deserializer<'de> {
+impl<'de> EnumAccess<'de> for &mut Deserializer<'de> {
     type Error = Error;
     type Variant = Self;
 
@@ -298,7 +1280,7 @@ impl<'de, 'a> EnumAccess<'de> for &'a mut Deserializer<'de> {
     }
 }
 
-impl<'de, 'a> VariantAccess<'de> for &'a mut Deserializer<'de> {
+impl<'de> VariantAccess<'de> for &mut Deserializer<'de> {
     type Error = Error;
 
     fn unit_variant(self) -> Result<()> {
@@ -306,7 +1288,7 @@ impl<'de, 'a> VariantAccess<'de> for &'a mut Deserializer<'de> {
         if self.parse_keyword("null")? {
             Ok(())
         } else {
-            Err(Error::Message("Expected 'null'".to_string()))
+            Err(self.unexpected("`null`"))
         }
     }
 
@@ -321,28 +1303,96 @@ impl<'de, 'a> VariantAccess<'de> for &'a mut Deserializer<'de> {
     where
         V: Visitor<'de>,
     {
-        let val = de::Deserializer::deserialize_seq(&mut *self, visitor)?;
-        self.trim_ignored()?;
-        if self.next()? != ']' {
-            Err(Error::Message("Expected ']'".to_string()))
-        } else {
-            Ok(val)
-        }
+        self.visit_tuple(visitor)
     }
 
-    fn struct_variant<V>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value>
+    fn struct_variant<V>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value>
     where
         V: Visitor<'de>,
     {
-        de::Deserializer::deserialize_map(&mut *self, visitor)
+        de::Deserializer::deserialize_struct(&mut *self, "", fields, visitor)
     }
 }
 
+fn closing_bracket(open: char) -> char {
+    if open == '[' {
+        ']'
+    } else {
+        '}'
+    }
+}
+
+/// Turn a string of hex digit pairs into bytes
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Visit a string, letting the visitor borrow it if it came straight from the
+/// input
+fn visit_str<'de, V>(s: Cow<'de, str>, visitor: V) -> Result<V::Value>
+where
+    V: Visitor<'de>,
+{
+    match s {
+        Cow::Borrowed(s) => visitor.visit_borrowed_str(s),
+        Cow::Owned(s) => visitor.visit_string(s),
+    }
+}
+
+/// Find a field that `key` is probably a typo of
+fn closest_field(key: &str, fields: &[&'static str]) -> Option<&'static str> {
+    fields
+        .iter()
+        .map(|field| (edit_distance(key, field), *field))
+        .filter(|(dist, field)| *dist <= std::cmp::max(1, field.chars().count() / 3))
+        .min_by_key(|(dist, _)| *dist)
+        .map(|(_, field)| field)
+}
+
+/// Edit distance between two strings in chars, where swapping two adjacent
+/// chars counts as a single edit (optimal string alignment distance)
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut dist = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in dist.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    dist[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            dist[i][j] = (dist[i - 1][j] + 1)
+                .min(dist[i][j - 1] + 1)
+                .min(dist[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                dist[i][j] = dist[i][j].min(dist[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    dist[a.len()][b.len()]
+}
+
 #[cfg(test)]
 mod test {
-    use serde::Deserialize;
+    use std::borrow::Cow;
+    use std::collections::{BTreeMap, HashMap};
+
+    use serde::de::IgnoredAny;
+    use serde::{Deserialize, Serialize};
+
+    use super::{from_str_with_options, DeserializerOptions, UnknownVariantPolicy};
+    use crate::test_util::Rng;
+    use crate::{Error, Position};
 
     #[derive(Deserialize, PartialEq, Debug)]
+    #[allow(clippy::enum_variant_names)]
     enum Enum {
         UnitVariant,
         NewTypeVariant(bool),
@@ -355,11 +1405,18 @@ mod test {
         seq: Vec<i32>,
     }
 
+    #[derive(Deserialize, PartialEq, Debug, Default)]
+    #[serde(default)]
+    struct Server {
+        host: String,
+        port: u32,
+    }
+
     #[test]
     fn test_literals() {
         assert_eq!((), super::from_str("null").unwrap());
-        assert_eq!(true, super::from_str("true").unwrap());
-        assert_eq!(false, super::from_str("false").unwrap());
+        assert!(super::from_str::<bool>("true").unwrap());
+        assert!(!super::from_str::<bool>("false").unwrap());
         assert_eq!("123a", super::from_str::<String>("123a").unwrap());
     }
 
@@ -401,4 +1458,1328 @@ mod test {
             super::from_str(paml).unwrap()
         );
     }
+
+    #[test]
+    fn test_key_warnings() {
+        use crate::error::Warning;
+
+        let input = r#"{ host localhost " port" 8080 }"#;
+        let (server, warnings) = super::from_str_with_warnings::<Server>(input).unwrap();
+        assert_eq!(0, server.port);
+        assert_eq!(
+            vec![Warning {
+                position: Position::new(input, 17),
+                message:
+                    r#"key " port" has whitespace inside the quotes and doesn't match field `port`"#
+                        .to_string()
+            }],
+            warnings
+        );
+
+        let (_, warnings) = super::from_str_with_warnings::<Server>("{ Port 8080 }").unwrap();
+        assert_eq!(
+            vec![Warning {
+                position: Position::new("{ Port 8080 }", 2),
+                message: "key `Port` doesn't match field `port` because keys are case-sensitive"
+                    .to_string()
+            }],
+            warnings
+        );
+
+        let (_, warnings) =
+            super::from_str_with_warnings::<Server>("{ host h prot 8080 }").unwrap();
+        assert_eq!(
+            vec![Warning {
+                position: Position::new("{ host h prot 8080 }", 9),
+                message: "unknown key `prot`, did you mean `port`?".to_string()
+            }],
+            warnings
+        );
+
+        // Keys of maps nested inside structs aren't checked against the struct's fields
+        let (_, warnings) = super::from_str_with_warnings::<Struct>("{ seq [1 2] }").unwrap();
+        assert!(warnings.is_empty());
+
+        let (server, warnings) =
+            super::from_str_with_warnings::<Server>("{ host localhost port 8080 }").unwrap();
+        assert_eq!(
+            Server {
+                host: "localhost".to_string(),
+                port: 8080
+            },
+            server
+        );
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert_eq!(
+            "tab\there\nnew\\line\r",
+            super::from_str::<String>(r#""tab\there\nnew\\line\r""#).unwrap()
+        );
+        assert_eq!(
+            "say \"hi\"",
+            super::from_str::<String>(r#""say \"hi\"""#).unwrap()
+        );
+        assert_eq!("it's", super::from_str::<String>(r"'it\'s'").unwrap());
+        assert_eq!(
+            "\u{e9}\u{1F600}",
+            super::from_str::<String>(r#""\u{e9}\u{1F600}""#).unwrap()
+        );
+        assert_eq!("\0A", super::from_str::<String>(r#""\0\x41""#).unwrap());
+        // Raw strings are left alone
+        assert_eq!(r"a\nb", super::from_str::<String>(r"`a\nb").unwrap());
+        assert_eq!("", super::from_str::<String>("`").unwrap());
+
+        assert!(matches!(
+            super::from_str::<String>(r#""ab\qc""#),
+            Err(crate::Error::InvalidEscape {
+                position: Position { offset: 3, .. }
+            })
+        ));
+        assert!(matches!(
+            super::from_str::<String>(r#""\u{110000}""#),
+            Err(crate::Error::InvalidEscape {
+                position: Position { offset: 1, .. }
+            })
+        ));
+        assert!(matches!(
+            super::from_str::<String>(r#""\u00e9""#),
+            Err(crate::Error::InvalidEscape {
+                position: Position { offset: 1, .. }
+            })
+        ));
+    }
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    enum Transport {
+        Tcp { port: u32 },
+        Udp(u32),
+        Disabled,
+    }
+
+    const INPUT: &str = "[~Tcp { port 80 } ~Quic { port 443 opts { a [1 ~X { b c }] } } ~Udp 53]";
+
+    fn with_policy(
+        input: &str,
+        policy: UnknownVariantPolicy,
+    ) -> crate::Result<(Vec<Transport>, Vec<String>)> {
+        let options = DeserializerOptions {
+            collect_warnings: true,
+            unknown_variant_policy: policy,
+            ..Default::default()
+        };
+        let (res, warnings) = from_str_with_options(input, options)?;
+        Ok((res, warnings.iter().map(|w| w.to_string()).collect()))
+    }
+
+    #[test]
+    fn test_error_policy() {
+        assert!(with_policy(INPUT, UnknownVariantPolicy::Error).is_err());
+    }
+
+    #[test]
+    fn test_unit_fallback() {
+        let (res, warnings) =
+            with_policy(INPUT, UnknownVariantPolicy::UnitFallback("Disabled")).unwrap();
+        assert_eq!(
+            vec![
+                Transport::Tcp { port: 80 },
+                Transport::Disabled,
+                Transport::Udp(53)
+            ],
+            res
+        );
+        assert_eq!(
+            vec![
+                "unknown variant `Quic` of Transport replaced with `Disabled` at line 1, column 19"
+            ],
+            warnings
+        );
+
+        for fallback in ["Nope", "Udp"] {
+            assert_eq!(
+                "Fallback `{}` is not a unit variant of Transport at line 1, column 19",
+                with_policy(INPUT, UnknownVariantPolicy::UnitFallback(fallback))
+                    .unwrap_err()
+                    .to_string()
+                    .replace(fallback, "{}")
+            );
+        }
+    }
+
+    /// Reads a [Transport], but turns errors into `None`
+    #[derive(PartialEq, Debug)]
+    struct Lenient(Option<Transport>);
+
+    impl<'de> Deserialize<'de> for Lenient {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            Ok(Lenient(Transport::deserialize(deserializer).ok()))
+        }
+    }
+
+    #[test]
+    fn test_skip_entry() {
+        let (res, warnings) = with_policy(INPUT, UnknownVariantPolicy::SkipEntry).unwrap();
+        assert_eq!(vec![Transport::Tcp { port: 80 }, Transport::Udp(53)], res);
+        assert_eq!(
+            vec!["skipped element with unknown variant `Quic` of Transport at line 1, column 19"],
+            warnings
+        );
+
+        // Unknown variants at the end of the list
+        let (res, warnings) = with_policy(
+            "[~Udp 1 ~Quic [1 2] ~Sctp null]",
+            UnknownVariantPolicy::SkipEntry,
+        )
+        .unwrap();
+        assert_eq!(vec![Transport::Udp(1)], res);
+        assert_eq!(2, warnings.len());
+
+        let (res, _) = with_policy("[~Quic null]", UnknownVariantPolicy::SkipEntry).unwrap();
+        assert!(res.is_empty());
+
+        // The end of the list is still noticed if the element's error is caught
+        let options = DeserializerOptions {
+            unknown_variant_policy: UnknownVariantPolicy::SkipEntry,
+            ..Default::default()
+        };
+        let (res, _) = from_str_with_options::<(Vec<Lenient>, Vec<Lenient>)>(
+            "[[~Udp 1 ~Quic null] [~Udp x]]",
+            options,
+        )
+        .unwrap();
+        assert_eq!(
+            (vec![Lenient(Some(Transport::Udp(1)))], vec![Lenient(None)]),
+            res
+        );
+
+        // Can't skip something that isn't a list element
+        let options = DeserializerOptions {
+            unknown_variant_policy: UnknownVariantPolicy::SkipEntry,
+            ..Default::default()
+        };
+        assert!(from_str_with_options::<Transport>("~Quic null", options).is_err());
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_eq!(-5, super::from_str::<i32>("-5").unwrap());
+        assert_eq!(5, super::from_str::<u8>("+5").unwrap());
+        assert_eq!(2.75, super::from_str::<f64>("2.75").unwrap());
+        assert_eq!(-0.5, super::from_str::<f32>("-5e-1").unwrap());
+        assert_eq!(1e10, super::from_str::<f64>("1e10").unwrap());
+        assert_eq!(2.0, super::from_str::<f64>("2").unwrap());
+        assert_eq!(
+            -9_000_000_000,
+            super::from_str::<i64>("-9000000000").unwrap()
+        );
+        assert_eq!(
+            u64::MAX,
+            super::from_str::<u64>("18446744073709551615").unwrap()
+        );
+        assert_eq!(
+            vec![1.5, -2.0, 3e-3],
+            super::from_str::<Vec<f64>>("[1.5 -2.0 3e-3]").unwrap()
+        );
+
+        assert_eq!(1_000, super::from_str::<i32>("1_000").unwrap());
+        assert_eq!(-1_000_000, super::from_str::<i64>("-1_000_000").unwrap());
+        assert_eq!(
+            1_234.567_89,
+            super::from_str::<f64>("1_234.567_89").unwrap()
+        );
+        assert_eq!(1e1_0, super::from_str::<f64>("1e1_0").unwrap());
+
+        assert_eq!(255, super::from_str::<u8>("0xFF").unwrap());
+        assert_eq!(0xff00, super::from_str::<i32>("0Xff_00").unwrap());
+        assert_eq!(-10, super::from_str::<i32>("-0b1010").unwrap());
+        assert_eq!(0o755, super::from_str::<u32>("+0o755").unwrap());
+        assert_eq!(
+            i64::MIN,
+            super::from_str::<i64>("-0x8000000000000000").unwrap()
+        );
+        assert_eq!(
+            u64::MAX,
+            super::from_str::<u64>("0xFFFF_FFFF_FFFF_FFFF").unwrap()
+        );
+        assert_eq!(
+            vec![10, 2, 8],
+            super::from_str::<Vec<i32>>("[0xa, 0b10, 0o10]").unwrap()
+        );
+
+        assert_eq!(
+            i128::MIN,
+            super::from_str::<i128>("-0x8000_0000_0000_0000_0000_0000_0000_0000").unwrap()
+        );
+        assert_eq!(
+            u128::MAX,
+            super::from_str::<u128>("0xFFFF_FFFF_FFFF_FFFF_FFFF_FFFF_FFFF_FFFF").unwrap()
+        );
+        assert_eq!(
+            -18446744073709551616,
+            super::from_str::<i128>("-18_446_744_073_709_551_616").unwrap()
+        );
+
+        assert_eq!(3.5, super::from_str::<f64>("3.5").unwrap());
+        assert_eq!(42, super::from_str::<i64>("42").unwrap());
+        assert_eq!(1e5, super::from_str::<f64>("1e5").unwrap());
+        assert_eq!(1.5e-7, super::from_str::<f64>("1.5E-7").unwrap());
+        assert_eq!(0.1f32, super::from_str::<f32>("0.1").unwrap());
+        #[derive(Deserialize, PartialEq, Debug)]
+        struct Floats {
+            a: f64,
+            b: f32,
+        }
+        assert_eq!(
+            Floats { a: 0.25, b: -3.0 },
+            super::from_str("{ a 0.25 b -3 }").unwrap()
+        );
+        // Floats aren't truncated to fit integers
+        assert!(super::from_str::<i64>("3.5").is_err());
+
+        // Things that only look a bit like numbers are strings
+        for s in [
+            "1.", "1.2.3", "1e", "-", "1-2", "--1", "1__2", "_1", "1_", "1_.5", "0x", "0xG",
+            "0b12", "0o8", "0x_1", "0B1", "0x1.5",
+        ] {
+            assert_eq!(s, super::from_str::<String>(s).unwrap());
+        }
+        // Numbers are still strings when a string is asked for
+        for s in ["-5", "1e5", "2.75"] {
+            assert_eq!(s, super::from_str::<String>(s).unwrap());
+        }
+
+        let err = super::from_str::<u8>("300").unwrap_err().to_string();
+        assert!(err.contains("300") && err.contains("u8"), "{}", err);
+        assert!(super::from_str::<u32>("-1").is_err());
+        assert!(matches!(
+            super::from_str::<Vec<u64>>("[18446744073709551616]"),
+            Err(crate::Error::Unexpected {
+                position: Some(Position { offset: 1, .. }),
+                ..
+            })
+        ));
+        assert!(super::from_str::<u64>("0x1_0000_0000_0000_0000").is_err());
+        assert!(super::from_str::<i64>("-0x8000000000000001").is_err());
+        assert!(matches!(
+            super::from_str::<Vec<u128>>("[340282366920938463463374607431768211456]"),
+            Err(crate::Error::NumberOutOfRange {
+                position: Position { offset: 1, .. }
+            })
+        ));
+        assert!(matches!(
+            super::from_str::<i128>("-0x8000_0000_0000_0000_0000_0000_0000_0001"),
+            Err(crate::Error::NumberOutOfRange { .. })
+        ));
+        assert!(matches!(
+            super::from_str::<f64>("1e999"),
+            Err(crate::Error::NumberOutOfRange {
+                position: Position { offset: 0, .. }
+            })
+        ));
+    }
+
+    #[test]
+    fn test_comments() {
+        let paml = "{ # comment before a key
+            seq # between key and value
+            [ #[ before the first element #] 0 1 # after an element
+            2 #[ nested #[ comment #] ] #] ] }  # at the end";
+        assert_eq!(
+            Struct { seq: vec![0, 1, 2] },
+            super::from_str(paml).unwrap()
+        );
+        assert_eq!(1, super::from_str::<i32>("1 #[ trailing #]\n").unwrap());
+        assert_eq!(1, super::from_str::<i32>("# leading\n1").unwrap());
+
+        let map = HashMap::from([("key".to_string(), "value".to_string())]);
+        assert_eq!(map, super::from_str("{ key # comment\n value }").unwrap());
+        assert_eq!(map, super::from_str("{ key #[ block #] value }").unwrap());
+        assert_eq!(map, super::from_str("{ key #[ a\n b #]value }").unwrap());
+        // A # inside a word or string isn't a comment
+        assert_eq!(
+            HashMap::from([("a#b".to_string(), "#c".to_string())]),
+            super::from_str(r##"{ a#b "#c" }"##).unwrap()
+        );
+
+        assert!(matches!(
+            super::from_str::<Vec<i32>>("[1 #[ never #[ closed #] ]"),
+            Err(crate::Error::UnterminatedComment {
+                position: Position { offset: 3, .. }
+            })
+        ));
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
+    struct UserId(u64);
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Config {
+        name: String,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
+    enum Color {
+        Red,
+        Green,
+    }
+
+    /// Serialize and deserialize again
+    fn round_trip<T>(val: &T) -> T
+    where
+        T: Serialize + for<'de> Deserialize<'de>,
+    {
+        super::from_str(&crate::to_string(val).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_keys() {
+        let chars = HashMap::from([('a', "x".to_string())]);
+        assert_eq!(chars, round_trip(&chars));
+        let chars = HashMap::from([
+            ('a', "x".to_string()),
+            ('1', "y".to_string()),
+            (' ', "z".to_string()),
+        ]);
+        assert_eq!(chars, super::from_str(r#"{ a x 1 y " " z }"#).unwrap());
+        assert_eq!(chars, round_trip(&chars));
+        assert!(super::from_str::<HashMap<char, String>>("{ ab x }").is_err());
+
+        let ids = HashMap::from([(UserId(7), "x".to_string())]);
+        assert_eq!(ids, round_trip(&ids));
+        let configs = HashMap::from([
+            (
+                UserId(1),
+                Config {
+                    name: "a".to_string(),
+                },
+            ),
+            (
+                UserId(2),
+                Config {
+                    name: "b".to_string(),
+                },
+            ),
+        ]);
+        assert_eq!(
+            configs,
+            super::from_str("{ 1 { name a } ~UserId 2 { name b } }").unwrap()
+        );
+        // Values still need the tag
+        assert!(super::from_str::<HashMap<String, UserId>>("{ a 1 }").is_err());
+
+        let colors = HashMap::from([(Color::Red, 1)]);
+        assert_eq!(colors, round_trip(&colors));
+        assert_eq!(
+            HashMap::from([(Color::Red, 1), (Color::Green, 2)]),
+            super::from_str("{ Red 1 ~Green null 2 }").unwrap()
+        );
+        assert!(super::from_str::<HashMap<Color, i32>>("{ Blue 1 }").is_err());
+
+        let nums = HashMap::from([(1u32, true), (20, false)]);
+        assert_eq!(nums, round_trip(&nums));
+        assert_eq!(
+            HashMap::from([(-1, 'a')]),
+            super::from_str("{ -1 a }").unwrap()
+        );
+        let strings = BTreeMap::from([
+            ("a".to_string(), 1),
+            ("true".to_string(), 2),
+            ("3".to_string(), 3),
+        ]);
+        assert_eq!(strings, round_trip(&strings));
+        // String keys don't need quotes even if they look like something else
+        assert_eq!(strings, super::from_str(r#"{ a 1 true 2 "3" 3 }"#).unwrap());
+        let tuples = HashMap::from([((1, 2), 3)]);
+        assert_eq!(tuples, round_trip(&tuples));
+
+        assert_eq!(
+            "Expected u32, found string \"a\" at line 1, column 3",
+            error_message::<HashMap<u32, i32>>("{ a 1 }")
+        );
+        assert_eq!(
+            "Expected a string, found sequence at line 1, column 3",
+            error_message::<HashMap<String, i32>>("{ [1] 1 }")
+        );
+        assert_eq!(
+            "Expected u32, found map at line 1, column 3",
+            error_message::<HashMap<u32, i32>>("{ { a 1 } 1 }")
+        );
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Optional {
+        a: Option<i32>,
+        b: Option<Color>,
+    }
+
+    #[test]
+    fn test_option() {
+        assert_eq!(Some(5), round_trip(&Some(5)));
+        assert_eq!(None, round_trip(&None::<i32>));
+        assert_eq!(Some(Some(5)), round_trip(&Some(Some(5))));
+        assert_eq!(Some(None), round_trip(&Some(None::<i32>)));
+        assert_eq!(vec![Some(1)], round_trip(&vec![Some(1)]));
+        let map = HashMap::from([("a".to_string(), None::<i32>)]);
+        assert_eq!(map, round_trip(&map));
+
+        // Plain values and null work too
+        assert_eq!(Some(5), super::from_str("5").unwrap());
+        assert_eq!(None::<i32>, super::from_str("null").unwrap());
+        assert_eq!(
+            vec![Some(1), None, Some(3), None],
+            super::from_str::<Vec<Option<i32>>>("[~Some 1 null 3 ~None null]").unwrap()
+        );
+        assert_eq!(
+            HashMap::from([("a".to_string(), Some(1)), ("b".to_string(), None),]),
+            super::from_str("{ a 1 b ~None null }").unwrap()
+        );
+        assert_eq!(
+            Optional {
+                a: None,
+                b: Some(Color::Red)
+            },
+            super::from_str("{ a null b ~Red null }").unwrap()
+        );
+        assert!(super::from_str::<Option<i32>>("~None 5").is_err());
+    }
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Counter {
+        name: Option<String>,
+        count: u32,
+    }
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Outer {
+        inner: Counter,
+    }
+
+    /// The position of a type error, checking that it says what was expected
+    fn error_position<'a, T: Deserialize<'a> + std::fmt::Debug>(
+        input: &'a str,
+        expected: &str,
+    ) -> Position {
+        match super::from_str::<T>(input).unwrap_err() {
+            Error::Unexpected {
+                expected: exp,
+                position: Some(position),
+                ..
+            } => {
+                assert_eq!(expected, exp);
+                position
+            }
+            err => panic!("Wrong error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_error_positions() {
+        let err = super::from_str::<Counter>("{ count notanumber }").unwrap_err();
+        assert_eq!(
+            r#"Expected u32, found string "notanumber" at line 1, column 9"#,
+            err.to_string()
+        );
+        assert_eq!(
+            Position {
+                offset: 8,
+                line: 1,
+                column: 9
+            },
+            error_position::<Counter>("{ count notanumber }", "u32")
+        );
+
+        let input = "{\n  inner {\n    name x\n    count -1\n  }\n}";
+        assert_eq!(
+            Position {
+                offset: input.find("-1").unwrap(),
+                line: 4,
+                column: 11
+            },
+            error_position::<Outer>(input, "u32")
+        );
+
+        // Columns are in chars, offsets are in bytes
+        let input = "{ name \"h\u{e9}llo \u{1F600}\" count x }";
+        assert_eq!(
+            Position {
+                offset: input.find(" x ").unwrap() + 1,
+                line: 1,
+                column: 24
+            },
+            error_position::<Counter>(input, "u32")
+        );
+
+        assert!(matches!(
+            super::from_str::<Counter>("{ count }"),
+            Err(Error::UnexpectedToken {
+                position: Position { offset: 8, .. },
+                got: '}',
+                expected: "a value",
+            })
+        ));
+
+        let err = super::from_str::<bool>("notabool").unwrap_err();
+        assert_eq!(
+            Position {
+                offset: 0,
+                line: 1,
+                column: 1
+            },
+            error_position::<bool>("notabool", "a boolean")
+        );
+        assert_eq!(
+            r#"Expected a boolean, found string "notabool" at line 1, column 1"#,
+            err.to_string()
+        );
+        assert_eq!(
+            "Expected a `~Type` tag at line 1, column 3",
+            super::from_str::<Vec<UserId>>("[ 1 ]")
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "Expected `]` to close the `[` at line 1, column 1, found `3` at line 1, column 6",
+            super::from_str::<(i32, i32)>("[1 2 3]")
+                .unwrap_err()
+                .to_string()
+        );
+        // Errors from visitors get the position of the last token read
+        assert_eq!(
+            "missing field `count` at line 1, column 10",
+            super::from_str::<Counter>("{ name x }")
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Point {
+        x: i32,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Line {
+        start: Point,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Unit;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Marked {
+        marker: Unit,
+        x: i32,
+    }
+
+    #[test]
+    fn test_struct_tags() {
+        let point = Point { x: 1 };
+        assert_eq!(point, round_trip(&point));
+        let line = Line {
+            start: Point { x: 2 },
+        };
+        assert_eq!(line, round_trip(&line));
+        let points = vec![Point { x: 3 }];
+        assert_eq!(points, round_trip(&points));
+        assert_eq!(Unit, round_trip(&Unit));
+        assert_eq!(Some(Unit), round_trip(&Some(Unit)));
+        let marked = Marked { marker: Unit, x: 4 };
+        assert_eq!(marked, round_trip(&marked));
+
+        // The tag is optional
+        assert_eq!(point, super::from_str("{ x 1 }").unwrap());
+        assert_eq!(line, super::from_str("{ start ~Point { x 2 } }").unwrap());
+        assert_eq!(
+            HashMap::from([("a".to_string(), 1)]),
+            super::from_str("~Whatever { a 1 }").unwrap()
+        );
+
+        assert_eq!(Unit, super::from_str("{ }").unwrap());
+        assert_eq!(Unit, super::from_str("~Unit null").unwrap());
+        assert_eq!(
+            "Expected `~Unit`, found `~Point` at line 1, column 1",
+            super::from_str::<Unit>("~Point {}")
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "Expected `{}` or `null`, found `1` at line 1, column 7",
+            super::from_str::<Unit>("~Unit 1").unwrap_err().to_string()
+        );
+        assert!(super::from_str::<Unit>("{ x 1 }").is_err());
+
+        let err = super::from_str::<Line>("~Line { start ~Line { x 2 } }").unwrap_err();
+        assert_eq!(
+            "Expected `~Point`, found `~Line` at line 1, column 15",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_separators() {
+        let server = Server {
+            host: "h".to_string(),
+            port: 80,
+        };
+        let map = HashMap::from([("a".to_string(), 1), ("b".to_string(), 2)]);
+        for paml in [
+            "{ host h, port 80 }",
+            "{ host h, port 80, }",
+            "{\n  host h\n  port 80\n}",
+            "{\n  host h,\n  port 80,\n}",
+            "{ host h port 80, }",
+            "{host h,port 80}",
+        ] {
+            assert_eq!(server, super::from_str(paml).unwrap(), "{}", paml);
+            let paml = paml.replace("host h", "a 1").replace("port 80", "b 2");
+            assert_eq!(map, super::from_str(&paml).unwrap(), "{}", paml);
+        }
+
+        for paml in ["[1, 2, 3]", "[1, 2, 3,]", "[1,2,3]", "[\n  1\n  2,\n  3\n]"] {
+            assert_eq!(vec![1, 2, 3], super::from_str::<Vec<i32>>(paml).unwrap());
+            assert_eq!((1, 2, 3), super::from_str(paml).unwrap());
+        }
+        assert_eq!(
+            vec![Transport::Udp(53), Transport::Disabled],
+            super::from_str::<Vec<Transport>>("[~Udp 53, ~Disabled null,]").unwrap()
+        );
+        // A comma is a word boundary
+        assert_eq!(
+            vec!["a".to_string(), "b".to_string()],
+            super::from_str::<Vec<String>>("[a,b]").unwrap()
+        );
+
+        // Only one comma per entry, and not before the first one
+        assert!(super::from_str::<Vec<i32>>("[1,, 2]").is_err());
+        assert!(super::from_str::<Vec<i32>>("[, 1]").is_err());
+        assert!(super::from_str::<HashMap<String, i32>>("{ a, 1 }").is_err());
+
+        // Output from the serializer can be read back
+        let maps = vec![map.clone(), map];
+        assert_eq!(maps, round_trip(&maps));
+        let lines = vec![
+            Line {
+                start: Point { x: 1 },
+            },
+            Line {
+                start: Point { x: 2 },
+            },
+        ];
+        assert_eq!(lines, round_trip(&lines));
+    }
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Defaults {
+        name: String,
+        retries: Option<u32>,
+        #[serde(default)]
+        verbose: bool,
+        #[serde(default = "default_level")]
+        level: i32,
+    }
+
+    fn default_level() -> i32 {
+        3
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(
+            Defaults {
+                name: "foo".to_string(),
+                retries: None,
+                verbose: false,
+                level: 3
+            },
+            super::from_str("{ name foo }").unwrap()
+        );
+        // Unknown keys are skipped, whatever their values look like
+        let paml = r#"{
+            extra ~Thing { a [1 { b "}" }, ~X null] c `to end of line }
+            }
+            name foo
+            other [[] {}]
+            retries 2
+            level 1
+        }"#;
+        assert_eq!(
+            Defaults {
+                name: "foo".to_string(),
+                retries: Some(2),
+                verbose: false,
+                level: 1
+            },
+            super::from_str(paml).unwrap()
+        );
+        assert!(super::from_str::<Defaults>("{ retries 2 }").is_err());
+    }
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Borrowed<'a> {
+        name: &'a str,
+        #[serde(borrow)]
+        note: Cow<'a, str>,
+    }
+
+    #[test]
+    fn test_borrowed_str() {
+        let paml = r#"{ name foo note "no escapes" }"#;
+        let borrowed: Borrowed = super::from_str(paml).unwrap();
+        assert_eq!("foo", borrowed.name);
+        assert!(matches!(borrowed.note, Cow::Borrowed("no escapes")));
+
+        let paml = "{ name 'quoted' note `to end of line\n}";
+        let borrowed: Borrowed = super::from_str(paml).unwrap();
+        assert_eq!("quoted", borrowed.name);
+        assert!(matches!(borrowed.note, Cow::Borrowed("to end of line")));
+
+        // Escapes have to be replaced, so they need an owned string
+        let paml = r#"{ name foo note "a\tb" }"#;
+        let borrowed: Borrowed = super::from_str(paml).unwrap();
+        assert!(matches!(borrowed.note, Cow::Owned(s) if s == "a\tb"));
+        assert!(super::from_str::<&str>(r#""a\tb""#).is_err());
+
+        let map: HashMap<&str, Vec<&str>> = super::from_str("{ a [b c] 'd' [] }").unwrap();
+        assert_eq!(HashMap::from([("a", vec!["b", "c"]), ("d", vec![])]), map);
+    }
+
+    /// Hands out the input a few bytes at a time, then fails if `fail` is set
+    struct ChunkedReader<'a> {
+        input: &'a [u8],
+        fail: bool,
+    }
+
+    impl std::io::Read for ChunkedReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.input.is_empty() && self.fail {
+                return Err(std::io::Error::other("disk on fire"));
+            }
+            let len = buf.len().min(self.input.len()).min(3);
+            buf[..len].copy_from_slice(&self.input[..len]);
+            self.input = &self.input[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let reader = ChunkedReader {
+            input: "{ name \"h\u{e9}llo \u{1F600}\" count 5 }".as_bytes(),
+            fail: false,
+        };
+        assert_eq!(
+            Counter {
+                name: Some("h\u{e9}llo \u{1F600}".to_string()),
+                count: 5
+            },
+            super::from_reader(reader).unwrap()
+        );
+
+        let reader = ChunkedReader {
+            input: b"[a b\xff\xfe c]",
+            fail: false,
+        };
+        assert!(matches!(
+            super::from_reader::<_, Vec<String>>(reader),
+            Err(Error::InvalidUtf8 { offset: 4 })
+        ));
+
+        let reader = ChunkedReader {
+            input: b"[1 2",
+            fail: true,
+        };
+        let err = super::from_reader::<_, Vec<i32>>(reader).unwrap_err();
+        assert_eq!("IO error: disk on fire", err.to_string());
+        assert!(std::error::Error::source(&err).is_some());
+    }
+
+    fn error_message<'a, T: Deserialize<'a> + std::fmt::Debug>(input: &'a str) -> String {
+        super::from_str::<T>(input).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_brackets() {
+        assert_eq!(
+            "Unmatched `]` at line 1, column 1, there's no open `[` to close",
+            error_message::<Vec<i32>>("]")
+        );
+        assert_eq!(
+            "Unmatched `}` at line 1, column 3, there's no open `{` to close",
+            error_message::<i32>("1 }")
+        );
+        assert_eq!(
+            "Unmatched `]` at line 1, column 4, there's no open `[` to close",
+            error_message::<Vec<i32>>("[1]]")
+        );
+        assert_eq!(
+            "Expected `]` to close the `[` at line 1, column 1, found `}` at line 1, column 2",
+            error_message::<Vec<i32>>("[}")
+        );
+        assert_eq!(
+            "Expected `}` to close the `{` at line 1, column 1, found `]` at line 1, column 2",
+            error_message::<HashMap<String, i32>>("{]")
+        );
+        assert_eq!(
+            "Expected `}` to close the `{` at line 1, column 3, found `]` at line 1, column 7",
+            error_message::<Vec<IgnoredAny>>("[ { a ] ]")
+        );
+        assert_eq!(
+            "Expected `}` to close the `{` at line 1, column 1, found `]` at line 1, column 11",
+            error_message::<Server>("{ port 80 ] }")
+        );
+        assert_eq!(
+            "`[` at line 1, column 1 is never closed",
+            error_message::<Vec<Vec<i32>>>("[[1]")
+        );
+        assert_eq!(
+            "`{` at line 2, column 3 is never closed",
+            error_message::<Line>("{ start\n  { x 1 ")
+        );
+        assert!(matches!(
+            super::from_str::<Vec<i32>>("[1 2"),
+            Err(Error::UnclosedDelimiter {
+                open_position: Position { offset: 0, .. },
+                delimiter: '['
+            })
+        ));
+    }
+
+    fn with_max_depth<'a, T: Deserialize<'a>>(
+        input: &'a str,
+        max_depth: usize,
+    ) -> crate::Result<T> {
+        let options = DeserializerOptions {
+            max_depth: Some(max_depth),
+            ..Default::default()
+        };
+        from_str_with_options(input, options).map(|(res, _)| res)
+    }
+
+    #[test]
+    fn test_max_depth() {
+        assert_eq!(
+            vec![vec![1]],
+            with_max_depth::<Vec<Vec<i32>>>("[[1]]", 2).unwrap()
+        );
+        assert_eq!(1, with_max_depth::<i32>("1", 0).unwrap());
+        assert_eq!(
+            "Nesting at line 1, column 2 is deeper than the maximum of 1",
+            with_max_depth::<Vec<Vec<i32>>>("[[1]]", 1)
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "Nesting at line 1, column 9 is deeper than the maximum of 1",
+            with_max_depth::<Line>("{ start { x 1 } }", 1)
+                .unwrap_err()
+                .to_string()
+        );
+        // Skipped values count too
+        let deep = format!("{}{}", "[".repeat(10_000), "]".repeat(10_000));
+        assert!(matches!(
+            with_max_depth::<IgnoredAny>(&deep, 64),
+            Err(Error::MaxDepthExceeded {
+                position: Position { offset: 64, .. },
+                max_depth: 64
+            })
+        ));
+        // Tags don't nest, so a long chain of them doesn't need deep recursion
+        let tags = format!("{}1", "~a ".repeat(1_000_000));
+        assert!(with_max_depth::<IgnoredAny>(&tags, 64).is_ok());
+    }
+
+    #[test]
+    fn test_max_string_len() {
+        let with_max_len = |input| {
+            let options = DeserializerOptions {
+                max_string_len: Some(3),
+                ..Default::default()
+            };
+            from_str_with_options::<Vec<String>>(input, options).map(|(res, _)| res)
+        };
+        assert_eq!(
+            vec!["abc", "def", "ghi", "AB"],
+            with_max_len(
+                r#"[abc "def" `ghi
+                '\x41\x42']"#
+            )
+            .unwrap()
+        );
+        for (input, column) in [
+            ("[abcd]", 2),
+            (r#"[ab "abcd"]"#, 5),
+            ("[`abcd\n]", 2),
+            ("[\"\\u{1F600}\"]", 2),
+        ] {
+            assert_eq!(
+                format!(
+                    "String at line 1, column {} is longer than the maximum of 3 bytes",
+                    column
+                ),
+                with_max_len(input).unwrap_err().to_string()
+            );
+        }
+        // Strings are checked while they're read, before any escapes are decoded
+        assert_eq!(
+            "String at line 1, column 2 is longer than the maximum of 3 bytes",
+            with_max_len(r#"["abcd\q"]"#).unwrap_err().to_string()
+        );
+        let huge = format!("[\"{}", "a".repeat(1_000_000));
+        assert!(matches!(
+            with_max_len(&huge),
+            Err(Error::StringTooLong {
+                position: Position { offset: 1, .. },
+                ..
+            })
+        ));
+        // Keys and skipped strings count too
+        let options = DeserializerOptions {
+            max_string_len: Some(3),
+            ..Default::default()
+        };
+        assert!(matches!(
+            from_str_with_options::<IgnoredAny>("{ abcd 1 }", options),
+            Err(Error::StringTooLong {
+                position: Position { offset: 2, .. },
+                max_len: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn test_strict_duplicates() {
+        let strict = DeserializerOptions {
+            strict_duplicates: true,
+            ..Default::default()
+        };
+        let with_strict = |input| {
+            from_str_with_options::<HashMap<String, i32>>(input, strict.clone()).map(|(res, _)| res)
+        };
+        // Lenient by default
+        assert_eq!(
+            HashMap::from([("a".to_string(), 2)]),
+            super::from_str::<HashMap<String, i32>>("{ a 1 a 2 }").unwrap()
+        );
+        assert_eq!(
+            HashMap::from([("a".to_string(), 1), ("b".to_string(), 2)]),
+            with_strict("{ a 1 b 2 }").unwrap()
+        );
+        assert_eq!(
+            "Duplicate key `a` at line 1, column 7",
+            with_strict("{ a 1 a 2 }").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "Duplicate key `a b` at line 1, column 11",
+            with_strict(r#"{ "a b" 1 'a b' 2 }"#)
+                .unwrap_err()
+                .to_string()
+        );
+        // Each map has its own keys
+        assert_eq!(
+            2,
+            from_str_with_options::<Vec<HashMap<String, i32>>>("[{ a 1 } { a 2 }]", strict.clone())
+                .unwrap()
+                .0
+                .len()
+        );
+        assert!(matches!(
+            from_str_with_options::<HashMap<String, HashMap<String, i32>>>(
+                "{ a { b 1 } c { b 1 b 2 } }",
+                strict.clone()
+            ),
+            Err(Error::DuplicateKey {
+                position: Position { offset: 20, .. },
+                ..
+            })
+        ));
+        assert!(matches!(
+            from_str_with_options::<HashMap<(i32, i32), i32>>("{ [1 2] 1 [1 2] 2 }", strict),
+            Err(Error::DuplicateKey { .. })
+        ));
+    }
+
+    /// Like serde_bytes::ByteBuf, goes through serialize_bytes and
+    /// deserialize_byte_buf instead of being treated as a list
+    #[derive(PartialEq, Debug)]
+    struct Bytes(Vec<u8>);
+
+    impl Serialize for Bytes {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Bytes {
+        fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct BytesVisitor;
+
+            impl<'de> serde::de::Visitor<'de> for BytesVisitor {
+                type Value = Bytes;
+
+                fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    formatter.write_str("bytes")
+                }
+
+                fn visit_bytes<E>(self, v: &[u8]) -> Result<Bytes, E> {
+                    Ok(Bytes(v.to_vec()))
+                }
+
+                fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Bytes, E> {
+                    Ok(Bytes(v))
+                }
+            }
+
+            deserializer.deserialize_byte_buf(BytesVisitor)
+        }
+    }
+
+    #[test]
+    fn test_bytes() {
+        let mut rng = Rng(0x853c_49e6_748f_ea9b);
+        let blob: Vec<u8> = (0..1024).map(|_| rng.next_u64() as u8).collect();
+        for bytes in [vec![], vec![0, 127, 255], blob] {
+            assert_eq!(bytes, round_trip(&bytes));
+            let bytes = Bytes(bytes);
+            assert_eq!(bytes, round_trip(&bytes));
+
+            let ser_options = crate::SerializerOptions {
+                hex_bytes: true,
+                ..Default::default()
+            };
+            let paml = crate::to_string_with_options(&bytes, ser_options).unwrap();
+            let options = DeserializerOptions {
+                hex_bytes: true,
+                ..Default::default()
+            };
+            let (res, _) = from_str_with_options::<Bytes>(&paml, options).unwrap();
+            assert_eq!(bytes, res);
+        }
+        assert_eq!(
+            "\"007fff\"",
+            crate::to_string_with_options(
+                &Bytes(vec![0, 127, 255]),
+                crate::SerializerOptions {
+                    hex_bytes: true,
+                    ..Default::default()
+                }
+            )
+            .unwrap()
+        );
+
+        // Strings are taken byte for byte
+        assert_eq!(
+            Bytes(b"a\"\xc3\xa9".to_vec()),
+            super::from_str(r#""a\"\u{e9}""#).unwrap()
+        );
+        assert_eq!(Bytes(b"abc".to_vec()), super::from_str("abc").unwrap());
+        assert_eq!(Bytes(vec![1, 2]), super::from_str("[1, 0x2]").unwrap());
+        assert_eq!(
+            "Expected u8, found integer `256` at line 1, column 4",
+            error_message::<Bytes>("[1 256]")
+        );
+        assert_eq!(
+            "Expected u8, found integer `-1` at line 1, column 2",
+            error_message::<Bytes>("[-1]")
+        );
+
+        let options = DeserializerOptions {
+            hex_bytes: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            "Invalid hex string \"0g\" at line 1, column 3",
+            from_str_with_options::<Vec<Bytes>>("[ 0g ]", options)
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Listener {
+        name: String,
+        port: u32,
+        point: Point,
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Flattened {
+        #[serde(flatten)]
+        listener: Listener,
+        extra: String,
+    }
+
+    #[test]
+    fn test_flatten() {
+        let outer = Flattened {
+            listener: Listener {
+                name: "n".to_string(),
+                port: 80,
+                point: Point { x: 1 },
+            },
+            extra: "e".to_string(),
+        };
+        let paml = crate::to_string(&outer).unwrap();
+        assert_eq!("{name n,port 80,point ~Point {x 1},extra e}", paml);
+        assert_eq!(outer, super::from_str(&paml).unwrap());
+        assert_eq!(
+            outer,
+            super::from_str("{ extra e point { x 1 } port 80 name n }").unwrap()
+        );
+        assert_eq!(
+            outer,
+            super::from_str_document("name n\nport 80\npoint ~Point { x 1 }\nextra e").unwrap()
+        );
+        assert!(super::from_str::<Flattened>("{ name n port 80 extra e }").is_err());
+        // Skipping a long chain of tags doesn't need deep recursion
+        let tags = format!(
+            "{{ name n port 80 extra e point {}{{ x 1 }} }}",
+            "~Point ".repeat(100_000)
+        );
+        assert_eq!(outer, super::from_str(&tags).unwrap());
+    }
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct Pair(i32, i32);
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    enum Shape {
+        Rect(i32, i32),
+    }
+
+    #[test]
+    fn test_tuples() {
+        assert_eq!((1, 2), super::from_str::<(i32, i32)>("[1 2]").unwrap());
+        assert_eq!((1, 2), super::from_str::<(i32, i32)>("[ 1, 2, ]").unwrap());
+        assert_eq!(Pair(1, 2), super::from_str("[1 2 # two\n]").unwrap());
+        assert_eq!(Shape::Rect(1, 2), super::from_str("~Rect [1 2]").unwrap());
+        assert_eq!(
+            vec![(1, 2), (3, 4)],
+            super::from_str::<Vec<(i32, i32)>>("[[1 2] [3 4]]").unwrap()
+        );
+
+        assert_eq!(
+            "Expected `]` to close the `[` at line 1, column 1, found `3` at line 1, column 6",
+            error_message::<(i32, i32)>("[1 2 3]")
+        );
+        assert_eq!(
+            "Expected `]` to close the `[` at line 1, column 1, found `3` at line 1, column 6",
+            error_message::<Pair>("[1 2 3]")
+        );
+        assert_eq!(
+            "Expected `]` to close the `[` at line 1, column 7, found `3` at line 1, column 12",
+            error_message::<Shape>("~Rect [1 2 3]")
+        );
+        assert_eq!(
+            "`[` at line 1, column 1 is never closed",
+            error_message::<(i32, i32)>("[1")
+        );
+        assert_eq!(
+            "`[` at line 1, column 1 is never closed",
+            error_message::<(i32, i32)>("[1 2")
+        );
+        assert!(super::from_str::<(i32, i32)>("[1]").is_err());
+    }
+
+    #[test]
+    fn test_document() {
+        let server = Server {
+            host: "h".to_string(),
+            port: 80,
+        };
+        assert_eq!(server, super::from_str_document("host h\nport 80").unwrap());
+        assert_eq!(server, super::from_str_document("host h port 80").unwrap());
+        assert_eq!(
+            server,
+            super::from_str_document("{ host h\nport 80 }").unwrap()
+        );
+        let paml = "# The server
+            host h # comment after a value
+            #[ block
+               comment #]
+            port 80,
+            # at the end";
+        assert_eq!(server, super::from_str_document(paml).unwrap());
+
+        let line = Line {
+            start: Point { x: 1 },
+        };
+        assert_eq!(line, super::from_str_document("start { x 1 }").unwrap());
+        assert_eq!(
+            HashMap::from([("a".to_string(), vec![1, 2]), ("b".to_string(), vec![])]),
+            super::from_str_document("a [1 2]\nb []").unwrap()
+        );
+
+        // A single value is just that value
+        assert!(super::from_str_document::<bool>("true").unwrap());
+        assert!(super::from_str_document::<bool>("  true # comment\n").unwrap());
+        assert_eq!(
+            Transport::Udp(1),
+            super::from_str_document("~Udp 1").unwrap()
+        );
+        assert_eq!(
+            HashMap::from([(true, false)]),
+            super::from_str_document("true false").unwrap()
+        );
+
+        assert_eq!(
+            "Expected a value, found `}` at line 1, column 13",
+            super::from_str_document::<Server>("host h port }")
+                .unwrap_err()
+                .to_string()
+        );
+        assert!(super::from_str_document::<Server>("host h port").is_err());
+        // Brackets still have to be closed
+        assert!(super::from_str_document::<Line>("start { x 1").is_err());
+    }
+
+    #[test]
+    fn test_document_options() {
+        let options = DeserializerOptions {
+            max_depth: Some(2),
+            max_string_len: Some(3),
+            strict_duplicates: true,
+            ..Default::default()
+        };
+        let document = |input| {
+            super::from_str_document_with_options::<HashMap<String, Vec<Vec<i32>>>>(
+                input,
+                options.clone(),
+            )
+        };
+        assert_eq!(
+            HashMap::from([("a".to_string(), vec![vec![1]])]),
+            document("a [[1]]").unwrap().0
+        );
+        assert!(matches!(
+            document("a [[[1]]]"),
+            Err(Error::MaxDepthExceeded { max_depth: 2, .. })
+        ));
+        assert!(matches!(
+            document("abcd [[1]]"),
+            Err(Error::StringTooLong { max_len: 3, .. })
+        ));
+        assert!(matches!(
+            document("a [] a []"),
+            Err(Error::DuplicateKey { .. })
+        ));
+
+        // The first value is only skipped within the limits, too
+        let deep = format!("a {}", "[".repeat(100_000));
+        assert!(matches!(
+            document(&deep),
+            Err(Error::MaxDepthExceeded { .. })
+        ));
+
+        let options = DeserializerOptions {
+            collect_warnings: true,
+            ..Default::default()
+        };
+        let (server, warnings) =
+            super::from_str_document_with_options::<Server>("host h\nPort 80", options).unwrap();
+        assert_eq!("h", server.host);
+        assert_eq!(1, warnings.len());
+    }
 }
@@ -0,0 +1,208 @@
+use std::fmt::{self, Display, Write};
+
+use crate::error::{Error, Warning};
+use crate::span::{SourceMap, Span};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        })
+    }
+}
+
+/// An error or warning along with where in the input it is, for showing to
+/// users
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Missing for errors that aren't at any particular place in the input
+    pub span: Option<Span>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Show the message, then the line the span starts on with carets under
+    /// the span. Spans that go past the end of the line only get carets up to
+    /// the end of it, and empty spans get a single caret.
+    pub fn render(&self, source: &str) -> String {
+        let mut out = format!("{}: {}\n", self.severity, self.message);
+        let span = match self.span {
+            Some(span) => span,
+            None => return out,
+        };
+        let map = SourceMap::new(source);
+        let (line, column) = map.line_col(span);
+        let text = map.line_text(line).unwrap_or("");
+        let text_len = text.chars().count();
+        // A span on the \n of a \r\n is past even the end-of-line caret
+        let column = column.min(text_len + 1);
+        let rest = text_len + 1 - column;
+        let len = source
+            .get(span.start..span.end)
+            .and_then(|s| s.lines().next())
+            .map_or(0, |s| s.chars().count())
+            .min(rest)
+            .max(1);
+
+        let gutter = " ".repeat(line.to_string().len());
+        // Writing to a String can't fail
+        let _ = writeln!(out, "{} |", gutter);
+        let _ = writeln!(out, "{} | {}", line, text);
+        let _ = writeln!(
+            out,
+            "{} | {}{}",
+            gutter,
+            " ".repeat(column - 1),
+            "^".repeat(len)
+        );
+        out
+    }
+}
+
+pub fn to_diagnostic(err: &Error) -> Diagnostic {
+    let len = match err {
+        Error::UnexpectedToken { got, .. } => got.len_utf8(),
+        _ => 0,
+    };
+    Diagnostic {
+        severity: Severity::Error,
+        span: err.offset().map(|start| Span {
+            start,
+            end: start + len,
+        }),
+        message: err.to_string(),
+    }
+}
+
+pub fn warning_to_diagnostic(warning: &Warning) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Warning,
+        span: Some(Span {
+            start: warning.position.offset,
+            end: warning.position.offset,
+        }),
+        message: warning.message.clone(),
+    }
+}
+
+/// Show an error from deserializing `source` along with the line it's on
+pub fn format_error_with_source(err: &Error, source: &str) -> String {
+    to_diagnostic(err).render(source)
+}
+
+/// Show a warning from deserializing `source` along with the line it's on
+pub fn format_warning_with_source(warning: &Warning, source: &str) -> String {
+    warning_to_diagnostic(warning).render(source)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{format_error_with_source, format_warning_with_source, Diagnostic, Severity};
+    use crate::span::Span;
+
+    #[test]
+    fn test_error() {
+        let source = "{\n  count notanumber\n}";
+        let err = crate::from_str::<HashMap<String, u32>>(source).unwrap_err();
+        assert_eq!(
+            r#"error: Expected u32, found string "notanumber" at line 2, column 9
+  |
+2 |   count notanumber
+  |         ^
+"#,
+            format_error_with_source(&err, source)
+        );
+
+        let source = "[1 2 \u{e9}]";
+        let err = crate::from_str::<(i32, i32)>(source).unwrap_err();
+        assert_eq!(
+            "error: Expected `]` to close the `[` at line 1, column 1, found `\u{e9}` at line 1, column 6
+  |
+1 | [1 2 \u{e9}]
+  |      ^
+",
+            format_error_with_source(&err, source)
+        );
+
+        // Errors without a position just get the message
+        let err = crate::from_str::<i32>(" ").unwrap_err();
+        assert_eq!(
+            "error: unexpected end of input\n",
+            format_error_with_source(&err, " ")
+        );
+    }
+
+    #[test]
+    fn test_warning() {
+        #[derive(serde::Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Server {
+            port: Option<u32>,
+        }
+        let source = "{ prot 80 }";
+        let (_, warnings) = crate::from_str_with_warnings::<Server>(source).unwrap();
+        assert_eq!(
+            "warning: unknown key `prot`, did you mean `port`?
+  |
+1 | { prot 80 }
+  |   ^
+",
+            format_warning_with_source(&warnings[0], source)
+        );
+    }
+
+    #[test]
+    fn test_spans() {
+        let source = "first\nsecond line\n";
+        let diagnostic = |start, end| {
+            Diagnostic {
+                severity: Severity::Error,
+                span: Some(Span { start, end }),
+                message: "oops".to_string(),
+            }
+            .render(source)
+        };
+        assert_eq!(
+            "error: oops\n  |\n2 | second line\n  |    ^^^^\n",
+            diagnostic(9, 13)
+        );
+        // Multi-line spans are cut off at the end of the first line
+        assert_eq!(
+            "error: oops\n  |\n1 | first\n  |   ^^^\n",
+            diagnostic(2, 10)
+        );
+        // Past the end of the line
+        assert_eq!(
+            "error: oops\n  |\n1 | first\n  |      ^\n",
+            diagnostic(5, 5)
+        );
+
+        let source = "ab\r\ncd";
+        let diagnostic = |start, end| {
+            Diagnostic {
+                severity: Severity::Error,
+                span: Some(Span { start, end }),
+                message: "oops".to_string(),
+            }
+            .render(source)
+        };
+        // On the \r and on the \n of a CRLF line break
+        for start in [2, 3] {
+            assert_eq!(
+                "error: oops\n  |\n1 | ab\n  |   ^\n",
+                diagnostic(start, start)
+            );
+        }
+        assert_eq!("error: oops\n  |\n1 | ab\n  |   ^\n", diagnostic(3, 4));
+    }
+}
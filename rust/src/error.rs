@@ -1,39 +1,287 @@
-use std;
 use std::fmt::{self, Display};
+use std::io;
 
 use serde::{de, ser};
 
+use crate::span::Position;
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
-    Message(String),
+    /// Any other error. The position is missing for errors from serializing,
+    /// and for ones that came from outside the deserializer.
+    Message {
+        msg: String,
+        position: Option<Position>,
+    },
     Eof,
     TrailingCharacters(String),
-    ExpectedType
+    /// A value that needs a `~Type` tag in front of it didn't have one
+    ExpectedType {
+        position: Position,
+    },
+    /// A character that can't go where it was found
+    UnexpectedToken {
+        position: Position,
+        got: char,
+        expected: &'static str,
+    },
+    /// A backslash in a quoted string that isn't followed by a valid escape
+    InvalidEscape {
+        position: Position,
+    },
+    /// A number too big (or small) to be represented
+    NumberOutOfRange {
+        position: Position,
+    },
+    /// A `#[` with no matching `#]`
+    UnterminatedComment {
+        position: Position,
+    },
+    /// The input had something other than what was expected there, e.g. a
+    /// string where the type being deserialized needed a number. The position
+    /// is only missing if the error came from outside the deserializer.
+    Unexpected {
+        expected: String,
+        found: String,
+        position: Option<Position>,
+    },
+    /// A `]` or `}` with no open bracket for it to close
+    UnmatchedEndDelimiter {
+        position: Position,
+        delimiter: char,
+    },
+    /// Something other than the `]` or `}` needed to close the innermost open
+    /// bracket, e.g. a `}` closing a `[`, or an extra element in a tuple
+    MismatchedDelimiter {
+        position: Position,
+        found: char,
+        open_position: Position,
+        expected: char,
+    },
+    /// A `[` or `{` that's still open at the end of the input
+    UnclosedDelimiter {
+        open_position: Position,
+        delimiter: char,
+    },
+    /// A `[` or `{` nested deeper than [crate::DeserializerOptions::max_depth]
+    /// allows
+    MaxDepthExceeded {
+        position: Position,
+        max_depth: usize,
+    },
+    /// A string longer than [crate::DeserializerOptions::max_string_len] allows
+    StringTooLong {
+        position: Position,
+        max_len: usize,
+    },
+    /// A key that was already in the map, when
+    /// [crate::DeserializerOptions::strict_duplicates] is set
+    DuplicateKey {
+        position: Position,
+        key: String,
+    },
+    /// Reading the input failed
+    Io(io::Error),
+    /// The input isn't valid UTF-8, starting at the given byte
+    InvalidUtf8 {
+        offset: usize,
+    },
+    /// The serializer wrote something that doesn't parse (only checked in
+    /// debug builds or with the `self-check` feature)
+    SelfCheckFailed {
+        output: String,
+        error: Box<Error>,
+    },
+}
+
+impl Error {
+    /// Byte offset into the input where the error happened, if known
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            Error::Message { position, .. } | Error::Unexpected { position, .. } => {
+                position.map(|p| p.offset)
+            }
+            Error::ExpectedType { position }
+            | Error::UnexpectedToken { position, .. }
+            | Error::InvalidEscape { position }
+            | Error::NumberOutOfRange { position }
+            | Error::UnterminatedComment { position }
+            | Error::UnmatchedEndDelimiter { position, .. }
+            | Error::MismatchedDelimiter { position, .. }
+            | Error::MaxDepthExceeded { position, .. }
+            | Error::StringTooLong { position, .. }
+            | Error::DuplicateKey { position, .. } => Some(position.offset),
+            Error::UnclosedDelimiter { open_position, .. } => Some(open_position.offset),
+            Error::InvalidUtf8 { offset } => Some(*offset),
+            Error::Eof
+            | Error::TrailingCharacters(_)
+            | Error::Io(_)
+            | Error::SelfCheckFailed { .. } => None,
+        }
+    }
 }
 
 impl ser::Error for Error {
     fn custom<T: Display>(msg: T) -> Self {
-        Error::Message(msg.to_string())
+        Error::Message {
+            msg: msg.to_string(),
+            position: None,
+        }
     }
 }
 
 impl de::Error for Error {
     fn custom<T: Display>(msg: T) -> Self {
-        Error::Message(msg.to_string())
+        Error::Message {
+            msg: msg.to_string(),
+            position: None,
+        }
+    }
+
+    fn invalid_type(unexp: de::Unexpected, exp: &dyn de::Expected) -> Self {
+        Error::Unexpected {
+            expected: exp.to_string(),
+            found: unexp.to_string(),
+            position: None,
+        }
+    }
+
+    fn invalid_value(unexp: de::Unexpected, exp: &dyn de::Expected) -> Self {
+        <Error as de::Error>::invalid_type(unexp, exp)
     }
 }
 
 impl Display for Error {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Error::Message(msg) => formatter.write_str(msg),
+            Error::Message { msg, position } => {
+                formatter.write_str(msg)?;
+                if let Some(position) = position {
+                    write!(formatter, " at {}", position)?;
+                }
+                Ok(())
+            }
             Error::Eof => formatter.write_str("unexpected end of input"),
-            Error::TrailingCharacters(end) => formatter.write_str(&format!("Found extra text at end of input: {}", end)),
-            Error::ExpectedType => formatter.write_str("Expected type")
+            Error::TrailingCharacters(end) => {
+                formatter.write_str(&format!("Found extra text at end of input: {}", end))
+            }
+            Error::ExpectedType { position } => {
+                write!(formatter, "Expected a `~Type` tag at {}", position)
+            }
+            Error::UnexpectedToken {
+                position,
+                got,
+                expected,
+            } => write!(
+                formatter,
+                "Expected {}, found `{}` at {}",
+                expected, got, position
+            ),
+            Error::InvalidEscape { position } => {
+                write!(formatter, "Invalid escape sequence at {}", position)
+            }
+            Error::NumberOutOfRange { position } => {
+                write!(formatter, "Number at {} is out of range", position)
+            }
+            Error::UnterminatedComment { position } => {
+                write!(
+                    formatter,
+                    "Comment starting at {} is never closed",
+                    position
+                )
+            }
+            Error::Unexpected {
+                expected,
+                found,
+                position,
+            } => {
+                write!(formatter, "Expected {}, found {}", expected, found)?;
+                if let Some(position) = position {
+                    write!(formatter, " at {}", position)?;
+                }
+                Ok(())
+            }
+            Error::UnmatchedEndDelimiter {
+                position,
+                delimiter,
+            } => write!(
+                formatter,
+                "Unmatched `{}` at {}, there's no open `{}` to close",
+                delimiter,
+                position,
+                if *delimiter == ']' { '[' } else { '{' }
+            ),
+            Error::MismatchedDelimiter {
+                position,
+                found,
+                open_position,
+                expected,
+            } => write!(
+                formatter,
+                "Expected `{}` to close the `{}` at {}, found `{}` at {}",
+                expected,
+                if *expected == ']' { '[' } else { '{' },
+                open_position,
+                found,
+                position
+            ),
+            Error::UnclosedDelimiter {
+                open_position,
+                delimiter,
+            } => write!(
+                formatter,
+                "`{}` at {} is never closed",
+                delimiter, open_position
+            ),
+            Error::MaxDepthExceeded {
+                position,
+                max_depth,
+            } => write!(
+                formatter,
+                "Nesting at {} is deeper than the maximum of {}",
+                position, max_depth
+            ),
+            Error::StringTooLong { position, max_len } => write!(
+                formatter,
+                "String at {} is longer than the maximum of {} bytes",
+                position, max_len
+            ),
+            Error::DuplicateKey { position, key } => {
+                write!(formatter, "Duplicate key `{}` at {}", key, position)
+            }
+            Error::Io(error) => write!(formatter, "IO error: {}", error),
+            Error::InvalidUtf8 { offset } => write!(formatter, "Invalid UTF-8 at byte {}", offset),
+            Error::SelfCheckFailed { output, error } => write!(
+                formatter,
+                "Serializer produced invalid output ({}): {}",
+                error, output
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::SelfCheckFailed { error, .. } => Some(error.as_ref()),
+            _ => None,
         }
     }
 }
 
-impl std::error::Error for Error {}
+/// Something suspicious in the input that didn't stop deserialization
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    /// Where in the input the problem starts
+    pub position: Position,
+    pub message: String,
+}
+
+impl Display for Warning {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{} at {}", self.message, self.position)
+    }
+}
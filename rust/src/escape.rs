@@ -0,0 +1,113 @@
+use std::fmt::{self, Display};
+
+/// A backslash in a string that isn't followed by a valid escape sequence
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnescapeError {
+    /// Byte offset of the backslash within the string
+    pub offset: usize,
+}
+
+impl Display for UnescapeError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "Invalid escape sequence at byte {}", self.offset)
+    }
+}
+
+impl std::error::Error for UnescapeError {}
+
+/// Replace the escape sequences in the contents of a quoted string with the
+/// characters they stand for. Supports `\\`, `\"`, `\'`, `\n`, `\t`, `\r`,
+/// `\0`, `\xNN` (ASCII only) and `\u{NNNN}`.
+pub fn unescape(s: &str) -> Result<String, UnescapeError> {
+    let mut res = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(backslash) = rest.find('\\') {
+        res.push_str(&rest[..backslash]);
+        let offset = s.len() - rest.len() + backslash;
+        let (c, len) = parse_escape(&rest[backslash + 1..]).ok_or(UnescapeError { offset })?;
+        res.push(c);
+        rest = &rest[backslash + 1 + len..];
+    }
+    res.push_str(rest);
+    Ok(res)
+}
+
+/// Parse an escape sequence after the backslash, returning the char it stands
+/// for and how many bytes it took up
+pub(crate) fn parse_escape(s: &str) -> Option<(char, usize)> {
+    match s.chars().next()? {
+        'n' => Some(('\n', 1)),
+        'r' => Some(('\r', 1)),
+        't' => Some(('\t', 1)),
+        '0' => Some(('\0', 1)),
+        c @ ('\\' | '"' | '\'') => Some((c, 1)),
+        'x' => {
+            let hex = s.get(1..3).filter(|hex| is_hex(hex))?;
+            let b = u8::from_str_radix(hex, 16).ok().filter(u8::is_ascii)?;
+            Some((char::from(b), 3))
+        }
+        'u' => {
+            let (hex, _) = s[1..].strip_prefix('{')?.split_once('}')?;
+            if hex.is_empty() || hex.len() > 6 || !is_hex(hex) {
+                return None;
+            }
+            let c = u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)?;
+            // The u, braces and digits
+            Some((c, hex.len() + 3))
+        }
+        _ => None,
+    }
+}
+
+fn is_hex(s: &str) -> bool {
+    s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{unescape, UnescapeError};
+
+    #[test]
+    fn test_simple() {
+        assert_eq!("no escapes", unescape("no escapes").unwrap());
+        assert_eq!("\\", unescape(r"\\").unwrap());
+        assert_eq!("\"", unescape(r#"\""#).unwrap());
+        assert_eq!("'", unescape(r"\'").unwrap());
+        assert_eq!("a\nb", unescape(r"a\nb").unwrap());
+        assert_eq!("\t", unescape(r"\t").unwrap());
+        assert_eq!("\r\n", unescape(r"\r\n").unwrap());
+        assert_eq!("\0", unescape(r"\0").unwrap());
+        assert_eq!("\\n", unescape(r"\\n").unwrap());
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!("A", unescape(r"\x41").unwrap());
+        assert_eq!("\x7f!", unescape(r"\x7f!").unwrap());
+        assert_eq!(Err(UnescapeError { offset: 0 }), unescape(r"\x80"));
+        assert_eq!(Err(UnescapeError { offset: 1 }), unescape(r"a\x4"));
+        assert_eq!(Err(UnescapeError { offset: 0 }), unescape(r"\x+1"));
+    }
+
+    #[test]
+    fn test_unicode() {
+        assert_eq!("\u{e9}", unescape(r"\u{e9}").unwrap());
+        assert_eq!(
+            "\u{1F600} \u{10FFFF}",
+            unescape(r"\u{1F600} \u{10FFFF}").unwrap()
+        );
+        // Offsets are in bytes, even after multibyte chars
+        assert_eq!(Err(UnescapeError { offset: 3 }), unescape("\u{e9}a\\u{}"));
+        assert_eq!(Err(UnescapeError { offset: 0 }), unescape(r"\u{110000}"));
+        assert_eq!(Err(UnescapeError { offset: 0 }), unescape(r"\u{D800}"));
+        assert_eq!(Err(UnescapeError { offset: 0 }), unescape(r"\u{1234567}"));
+        assert_eq!(Err(UnescapeError { offset: 0 }), unescape(r"\u{e9"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(Err(UnescapeError { offset: 2 }), unescape(r"ab\q"));
+        assert_eq!(Err(UnescapeError { offset: 1 }), unescape("a\\"));
+        assert_eq!(Err(UnescapeError { offset: 0 }), unescape("\\\u{e9}"));
+    }
+}
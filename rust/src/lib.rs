@@ -1,10 +1,28 @@
 mod de;
+mod diagnostic;
 mod error;
+mod escape;
 mod ser;
+mod span;
+#[cfg(test)]
+mod test_util;
 
-pub use de::{from_str, Deserializer};
-pub use error::{Error, Result};
-pub use ser::{to_string, Serializer};
+pub use de::{
+    from_reader, from_str, from_str_document, from_str_document_with_options,
+    from_str_with_options, from_str_with_warnings, Deserializer, DeserializerOptions,
+    UnknownVariantPolicy,
+};
+pub use diagnostic::{
+    format_error_with_source, format_warning_with_source, to_diagnostic, warning_to_diagnostic,
+    Diagnostic, Severity,
+};
+pub use error::{Error, Result, Warning};
+pub use escape::{unescape, UnescapeError};
+pub use ser::{
+    to_string, to_string_with_options, to_writer, to_writer_with_options, Serializer,
+    SerializerOptions,
+};
+pub use span::{Position, SourceMap, Span};
 
 pub fn add(left: usize, right: usize) -> usize {
     left + right
@@ -1,30 +1,162 @@
+use std::io;
+
 use serde::{ser, Serialize};
 
 use crate::error::{Error, Result};
 
-pub struct Serializer {
-    output: String,
+#[derive(Debug, Clone, Default)]
+pub struct SerializerOptions {
+    /// Whether to put quotes around every string, even ones that would read
+    /// back the same as bare words
+    pub always_quote_strings: bool,
+    /// Whether to write byte arrays as strings of hex digits instead of lists
+    /// of numbers. Read them back with [crate::DeserializerOptions::hex_bytes].
+    pub hex_bytes: bool,
+}
+
+pub struct Serializer<W> {
+    writer: W,
+    options: SerializerOptions,
+    /// Whether nothing has been written yet in the list or map currently
+    /// being serialized, i.e. whether the next item needs a separator
+    first: bool,
+}
+
+impl<W: io::Write> Serializer<W> {
+    pub fn new(writer: W) -> Self {
+        Serializer::with_options(writer, SerializerOptions::default())
+    }
+
+    pub fn with_options(writer: W, options: SerializerOptions) -> Self {
+        Serializer {
+            writer,
+            options,
+            first: true,
+        }
+    }
+
+    /// Get back the writer that was serialized into
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write(&mut self, s: &str) -> Result<()> {
+        self.writer.write_all(s.as_bytes()).map_err(Error::Io)
+    }
 }
 
 pub fn to_string<T>(value: &T) -> Result<String>
 where
-    T: Serialize,
+    T: ?Sized + Serialize,
 {
-    let mut serializer = Serializer {
-        output: String::new(),
-    };
+    to_string_with_options(value, SerializerOptions::default())
+}
+
+/// Like [to_string], but with the given options
+pub fn to_string_with_options<T>(value: &T, options: SerializerOptions) -> Result<String>
+where
+    T: ?Sized + Serialize,
+{
+    let mut serializer = Serializer::with_options(Vec::new(), options);
     value.serialize(&mut serializer)?;
-    Ok(serializer.output)
+    let output = serializer.into_inner();
+    let output = String::from_utf8(output).expect("Serializer only writes UTF-8");
+    #[cfg(any(debug_assertions, feature = "self-check"))]
+    self_check(&output)?;
+    Ok(output)
 }
 
-/// Write the type for the value that follows
-#[must_use]
-fn serialize_type(s: &mut Serializer, typ: &str) -> Result<()> {
-    s.output += &format!("~{} ", typ);
+/// Serialize into `writer`. In debug builds or with the `self-check` feature,
+/// the output is buffered and checked like [to_string] does before anything is
+/// written, so `writer` never gets invalid output.
+pub fn to_writer<W, T>(writer: W, value: &T) -> Result<()>
+where
+    W: io::Write,
+    T: ?Sized + Serialize,
+{
+    to_writer_with_options(writer, value, SerializerOptions::default())
+}
+
+/// Like [to_writer], but with the given options
+pub fn to_writer_with_options<W, T>(
+    mut writer: W,
+    value: &T,
+    options: SerializerOptions,
+) -> Result<()>
+where
+    W: io::Write,
+    T: ?Sized + Serialize,
+{
+    if cfg!(any(debug_assertions, feature = "self-check")) {
+        let output = to_string_with_options(value, options)?;
+        writer.write_all(output.as_bytes()).map_err(Error::Io)
+    } else {
+        let mut serializer = Serializer::with_options(writer, options);
+        value.serialize(&mut serializer)
+    }
+}
+
+/// Make sure the serializer's output parses. If it doesn't, that's a bug in
+/// the serializer, so debug builds panic. This doesn't catch output that
+/// parses but reads back as a different value, e.g. a string that looks like
+/// a number, since without a type to read it into there's nothing to compare.
+#[cfg(any(debug_assertions, feature = "self-check"))]
+fn self_check(output: &str) -> Result<()> {
+    match crate::from_str::<serde::de::IgnoredAny>(output) {
+        Ok(_) => Ok(()),
+        Err(error) if cfg!(debug_assertions) => {
+            panic!("Serializer produced invalid output ({}): {}", error, output)
+        }
+        Err(error) => Err(Error::SelfCheckFailed {
+            output: output.to_string(),
+            error: Box::new(error),
+        }),
+    }
+}
+
+/// Write a separator unless this is the first item in the list or map
+fn serialize_separator<W: io::Write>(s: &mut Serializer<W>) -> Result<()> {
+    if !s.first {
+        s.write(",")?;
+    }
+    s.first = false;
     Ok(())
 }
 
-impl<'a> ser::Serializer for &'a mut Serializer {
+/// Whether `s` can be written without quotes and still be read back as the
+/// same string, rather than as a number, a keyword, or more than one word
+fn is_bare_word(s: &str) -> bool {
+    let mut chars = s.chars();
+    let number = match chars.next() {
+        None => return false,
+        Some('-' | '+') => chars.next().is_some_and(|c| c.is_ascii_digit()),
+        Some(c) => c.is_ascii_digit(),
+    };
+    !number
+        && !s.starts_with('~')
+        && !matches!(s, "true" | "false" | "null")
+        && !s.contains(|c: char| c.is_whitespace() || ",[]{}#'\"`".contains(c))
+}
+
+/// Infinities and NaN would be written as words that read back as strings,
+/// so they can't be serialized
+fn check_finite(v: f64) -> Result<()> {
+    if v.is_finite() {
+        Ok(())
+    } else {
+        Err(ser::Error::custom(format!(
+            "Can't serialize {}, only finite floats are supported",
+            v
+        )))
+    }
+}
+
+/// Write the type for the value that follows
+fn serialize_type<W: io::Write>(s: &mut Serializer<W>, typ: &str) -> Result<()> {
+    write!(s.writer, "~{} ", typ).map_err(Error::Io)
+}
+
+impl<W: io::Write> ser::Serializer for &mut Serializer<W> {
     type Ok = ();
 
     type Error = Error;
@@ -44,8 +176,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     type SerializeStructVariant = Self;
 
     fn serialize_bool(self, v: bool) -> Result<()> {
-        self.output += if v { "true" } else { "false" };
-        Ok(())
+        self.write(if v { "true" } else { "false" })
     }
 
     fn serialize_i8(self, v: i8) -> Result<()> {
@@ -61,8 +192,7 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     }
 
     fn serialize_i64(self, v: i64) -> Result<()> {
-        self.output += &v.to_string();
-        Ok(())
+        write!(self.writer, "{}", v).map_err(Error::Io)
     }
 
     fn serialize_u8(self, v: u8) -> Result<()> {
@@ -78,17 +208,29 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     }
 
     fn serialize_u64(self, v: u64) -> Result<()> {
-        self.output += &v.to_string();
-        Ok(())
+        write!(self.writer, "{}", v).map_err(Error::Io)
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<()> {
+        write!(self.writer, "{}", v).map_err(Error::Io)
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<()> {
+        write!(self.writer, "{}", v).map_err(Error::Io)
     }
 
     fn serialize_f32(self, v: f32) -> Result<()> {
-        self.serialize_f64(f64::from(v))
+        check_finite(f64::from(v))?;
+        // Not widened to f64 first, or 0.1 would come out as 0.10000000149011612
+        write!(self.writer, "{:?}", v).map_err(Error::Io)
     }
 
     fn serialize_f64(self, v: f64) -> Result<()> {
-        self.output += &v.to_string();
-        Ok(())
+        check_finite(v)?;
+        // Like Display, Debug prints the fewest digits that parse back to the
+        // same value, but it switches to exponent notation for very large and
+        // very small values instead of writing out hundreds of zeroes
+        write!(self.writer, "{:?}", v).map_err(Error::Io)
     }
 
     fn serialize_char(self, v: char) -> Result<()> {
@@ -96,17 +238,31 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     }
 
     fn serialize_str(self, v: &str) -> Result<()> {
-        self.output += "\"";
-        self.output += &v
-            .replace("\\", "\\\\")
-            .replace("\"", "\\\"")
-            .replace("\n", "\\n")
-            .replace("\r", "\\r");
-        self.output += "\"";
-        Ok(())
+        if !self.options.always_quote_strings && is_bare_word(v) {
+            return self.write(v);
+        }
+        // Single quotes save escaping double quotes, as long as there aren't
+        // any single quotes too
+        let quote = if v.contains('"') && !v.contains('\'') {
+            "'"
+        } else {
+            "\""
+        };
+        self.write(quote)?;
+        self.write(
+            &v.replace("\\", "\\\\")
+                .replace(quote, &format!("\\{}", quote))
+                .replace("\n", "\\n")
+                .replace("\r", "\\r"),
+        )?;
+        self.write(quote)
     }
 
     fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        if self.options.hex_bytes {
+            let hex: String = v.iter().map(|b| format!("{:02x}", b)).collect();
+            return self.serialize_str(&hex);
+        }
         use ser::SerializeSeq;
         let mut s = self.serialize_seq(Some(v.len()))?;
         for b in v {
@@ -119,16 +275,15 @@ impl<'a> ser::Serializer for &'a mut Serializer {
         self.serialize_unit_variant("Option", 0, "None")
     }
 
-    fn serialize_some<T: ?Sized>(self, value: &T) -> Result<()>
+    fn serialize_some<T>(self, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         self.serialize_newtype_variant("Option", 0, "Some", value)
     }
 
     fn serialize_unit(self) -> Result<()> {
-        self.output += "null";
-        Ok(())
+        self.write("null")
     }
 
     fn serialize_unit_struct(self, name: &'static str) -> Result<()> {
@@ -139,44 +294,39 @@ impl<'a> ser::Serializer for &'a mut Serializer {
 
     fn serialize_unit_variant(
         self,
-        name: &'static str,
+        _name: &'static str,
         _variant_index: u32,
-        _variant: &'static str,
+        variant: &'static str,
     ) -> Result<()> {
-        serialize_type(self, name)?;
+        serialize_type(self, variant)?;
         self.serialize_unit()
     }
 
-    fn serialize_newtype_struct<T: ?Sized>(self, name: &'static str, value: &T) -> Result<()>
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
-        use ser::SerializeTupleStruct;
         serialize_type(self, name)?;
-        let mut s = self.serialize_struct(name, 1)?;
-        s.serialize_field(value)?;
-        s.end()
+        value.serialize(self)
     }
 
-    fn serialize_newtype_variant<T: ?Sized>(
+    fn serialize_newtype_variant<T>(
         self,
-        name: &'static str,
-        variant_index: u32,
+        _name: &'static str,
+        _variant_index: u32,
         variant: &'static str,
         value: &T,
     ) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
-        use ser::SerializeTupleVariant;
         serialize_type(self, variant)?;
-        let mut tv = self.serialize_tuple_variant(name, variant_index, variant, 1)?;
-        tv.serialize_field(value)?;
-        tv.end()
+        value.serialize(self)
     }
 
     fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
-        self.output += "[";
+        self.write("[")?;
+        self.first = true;
         Ok(self)
     }
 
@@ -204,7 +354,8 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     }
 
     fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
-        self.output += "{";
+        self.write("{")?;
+        self.first = true;
         Ok(self)
     }
 
@@ -224,34 +375,35 @@ impl<'a> ser::Serializer for &'a mut Serializer {
     }
 }
 
-impl<'a> ser::SerializeSeq for &'a mut Serializer {
+impl<W: io::Write> ser::SerializeSeq for &mut Serializer<W> {
     type Ok = ();
 
     type Error = Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<()>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
-        value.serialize(&mut **self)?;
-        self.output += ",";
-        Ok(())
+        serialize_separator(self)?;
+        value.serialize(&mut **self)
     }
 
     fn end(self) -> Result<()> {
-        self.output += "]";
+        self.write("]")?;
+        // The list is itself an item of whatever contains it
+        self.first = false;
         Ok(())
     }
 }
 
-impl<'a> ser::SerializeTuple for &'a mut Serializer {
+impl<W: io::Write> ser::SerializeTuple for &mut Serializer<W> {
     type Ok = ();
 
     type Error = Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<()>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         ser::SerializeSeq::serialize_element(self, value)
     }
@@ -261,13 +413,13 @@ impl<'a> ser::SerializeTuple for &'a mut Serializer {
     }
 }
 
-impl<'a> ser::SerializeTupleStruct for &'a mut Serializer {
+impl<W: io::Write> ser::SerializeTupleStruct for &mut Serializer<W> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<()>
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         ser::SerializeTuple::serialize_element(self, value)
     }
@@ -277,13 +429,13 @@ impl<'a> ser::SerializeTupleStruct for &'a mut Serializer {
     }
 }
 
-impl<'a> ser::SerializeTupleVariant for &'a mut Serializer {
+impl<W: io::Write> ser::SerializeTupleVariant for &mut Serializer<W> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<()>
+    fn serialize_field<T>(&mut self, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         ser::SerializeSeq::serialize_element(self, value)
     }
@@ -293,45 +445,40 @@ impl<'a> ser::SerializeTupleVariant for &'a mut Serializer {
     }
 }
 
-impl<'a> ser::SerializeMap for &'a mut Serializer {
+impl<W: io::Write> ser::SerializeMap for &mut Serializer<W> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_key<T: ?Sized>(&mut self, key: &T) -> Result<()>
+    fn serialize_key<T>(&mut self, key: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
+        serialize_separator(self)?;
         key.serialize(&mut **self)?;
-        self.output += " ";
-        Ok(())
+        self.write(" ")
     }
 
-    fn serialize_value<T: ?Sized>(&mut self, value: &T) -> Result<()>
+    fn serialize_value<T>(&mut self, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
-        value.serialize(&mut **self)?;
-        self.output += ",";
-        Ok(())
+        value.serialize(&mut **self)
     }
 
     fn end(self) -> Result<()> {
-        self.output += "}";
+        self.write("}")?;
+        self.first = false;
         Ok(())
     }
 }
 
-impl<'a> ser::SerializeStruct for &'a mut Serializer {
+impl<W: io::Write> ser::SerializeStruct for &mut Serializer<W> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(
-        &mut self,
-        key: &'static str,
-        value: &T,
-    ) -> Result<()>
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         ser::SerializeMap::serialize_entry(self, key, value)
     }
@@ -341,17 +488,13 @@ impl<'a> ser::SerializeStruct for &'a mut Serializer {
     }
 }
 
-impl<'a> ser::SerializeStructVariant for &'a mut Serializer {
+impl<W: io::Write> ser::SerializeStructVariant for &mut Serializer<W> {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(
-        &mut self,
-        key: &'static str,
-        value: &T,
-    ) -> Result<()>
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         ser::SerializeMap::serialize_entry(self, key, value)
     }
@@ -360,3 +503,411 @@ impl<'a> ser::SerializeStructVariant for &'a mut Serializer {
         ser::SerializeMap::end(self)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use serde::de::DeserializeOwned;
+    use serde::{Deserialize, Serialize};
+
+    use crate::test_util::Rng;
+
+    #[derive(Serialize)]
+    struct Struct {
+        a: i32,
+        b: Vec<i32>,
+    }
+
+    /// Number of significant digits in a float written by the serializer
+    fn sig_digits(text: &str) -> usize {
+        let mantissa = text.split('e').next().unwrap();
+        let digits = mantissa.trim_start_matches('-').replace('.', "");
+        let digits = digits.trim_start_matches('0').trim_end_matches('0');
+        digits.len().max(1)
+    }
+
+    /// Check that `text` parses back to exactly `bits` and that no shorter
+    /// decimal would have
+    macro_rules! check_float {
+        ($ty:ty, $v:expr) => {{
+            let v: $ty = $v;
+            let text = super::to_string(&v).unwrap();
+            let parsed: $ty = crate::from_str(&text).unwrap();
+            assert_eq!(v.to_bits(), parsed.to_bits(), "{} from {:?}", text, v);
+
+            let digits = sig_digits(&text);
+            if digits > 1 {
+                let shorter = format!("{:.*e}", digits - 2, v);
+                let shorter: $ty = shorter.parse().unwrap();
+                assert_ne!(v.to_bits(), shorter.to_bits(), "{} isn't shortest", text);
+            }
+        }};
+    }
+
+    #[test]
+    fn test_floats() {
+        assert_eq!("0.1", super::to_string(&0.1_f32).unwrap());
+        assert_eq!("0.1", super::to_string(&0.1_f64).unwrap());
+        assert_eq!("-2.5", super::to_string(&-2.5_f32).unwrap());
+        assert_eq!("1.0", super::to_string(&1.0_f64).unwrap());
+        assert_eq!("1e300", super::to_string(&1e300_f64).unwrap());
+        assert_eq!("3.4028235e38", super::to_string(&f32::MAX).unwrap());
+
+        assert_eq!(
+            "Can't serialize inf, only finite floats are supported",
+            super::to_string(&vec![f64::INFINITY])
+                .unwrap_err()
+                .to_string()
+        );
+        assert!(super::to_string(&f32::NEG_INFINITY).is_err());
+        assert!(super::to_string(&f64::NAN).is_err());
+    }
+
+    #[test]
+    fn test_random_floats() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut checked = 0;
+        while checked < 10_000 {
+            let bits = rng.next_u64();
+            let f = f32::from_bits(bits as u32);
+            let d = f64::from_bits(bits);
+            if f.is_finite() {
+                check_float!(f32, f);
+            }
+            if d.is_finite() {
+                check_float!(f64, d);
+            }
+            checked += 1;
+        }
+    }
+
+    #[test]
+    fn test_quotes() {
+        assert_eq!(r#"'say "hi"'"#, super::to_string(r#"say "hi""#).unwrap());
+        assert_eq!(r#""it's""#, super::to_string("it's").unwrap());
+        assert_eq!(r#""'\"'""#, super::to_string(r#"'"'"#).unwrap());
+        assert_eq!(r#"'a\\"b'"#, super::to_string(r#"a\"b"#).unwrap());
+        assert_eq!(
+            r#"{'"k"' '"v"'}"#,
+            super::to_string(&BTreeMap::from([(r#""k""#, r#""v""#)])).unwrap()
+        );
+    }
+
+    /// Any string serializes to something that reads back as the same string
+    #[test]
+    fn test_random_strings() {
+        const CHARS: &[char] = &[
+            'a',
+            'Z',
+            '0',
+            '-',
+            ' ',
+            '\t',
+            '\n',
+            '\r',
+            '\0',
+            '"',
+            '\'',
+            '`',
+            '\\',
+            ',',
+            '[',
+            ']',
+            '{',
+            '}',
+            '#',
+            '~',
+            '_',
+            '.',
+            '\u{e9}',
+            '\u{1F600}',
+        ];
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..2_000 {
+            let len = rng.next_u64() as usize % 12;
+            let s: String = (0..len)
+                .map(|_| match rng.next_u64() % 8 {
+                    // Occasionally any char at all
+                    0 => char::from_u32(rng.next_u64() as u32 % 0x11_0000).unwrap_or('?'),
+                    _ => CHARS[rng.next_u64() as usize % CHARS.len()],
+                })
+                .collect();
+
+            let paml = super::to_string(&s).unwrap();
+            assert_eq!(s, crate::from_str::<String>(&paml).unwrap(), "{}", paml);
+            let map = BTreeMap::from([(s.clone(), vec![s.clone(), s.clone()])]);
+            let paml = super::to_string(&map).unwrap();
+            assert_eq!(map, crate::from_str(&paml).unwrap(), "{}", paml);
+        }
+    }
+
+    #[test]
+    fn test_128_bit() {
+        fn round_trip<T>(v: T)
+        where
+            T: Serialize + for<'de> serde::Deserialize<'de> + PartialEq + std::fmt::Debug,
+        {
+            let paml = super::to_string(&v).unwrap();
+            assert_eq!(v, crate::from_str::<T>(&paml).unwrap(), "{}", paml);
+        }
+
+        assert_eq!(
+            "340282366920938463463374607431768211455",
+            super::to_string(&u128::MAX).unwrap()
+        );
+        round_trip(i128::MAX);
+        round_trip(i128::MIN);
+        round_trip(u128::MAX);
+        round_trip(u128::from(u64::MAX) + 1);
+        round_trip(i128::from(i64::MAX) + 1);
+        round_trip(i128::from(i64::MIN) - 1);
+        round_trip(vec![0u128, 1, u128::MAX]);
+        // Small values still fit whatever type is asked for
+        round_trip(5i128);
+        round_trip(-5i128);
+    }
+
+    #[test]
+    fn test_separators() {
+        assert_eq!("[]", super::to_string(&Vec::<i32>::new()).unwrap());
+        assert_eq!("[1]", super::to_string(&vec![1]).unwrap());
+        assert_eq!("[1,2,3]", super::to_string(&vec![1, 2, 3]).unwrap());
+        assert_eq!(
+            "[[],[1,2],[]]",
+            super::to_string(&vec![vec![], vec![1, 2], vec![]]).unwrap()
+        );
+
+        let mut map = BTreeMap::new();
+        assert_eq!("{}", super::to_string(&map).unwrap());
+        map.insert("a", vec![]);
+        assert_eq!(r#"{a []}"#, super::to_string(&map).unwrap());
+        map.insert("b", vec![1, 2]);
+        assert_eq!(r#"{a [],b [1,2]}"#, super::to_string(&map).unwrap());
+
+        let s = Struct { a: 1, b: vec![] };
+        assert_eq!(r#"~Struct {a 1,b []}"#, super::to_string(&s).unwrap());
+        assert_eq!(
+            r#"[~Struct {a 1,b []},[1,2]]"#,
+            super::to_string(&(s, (1, 2))).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_bare_strings() {
+        assert_eq!("production", super::to_string("production").unwrap());
+        assert_eq!("a-b.c/d", super::to_string("a-b.c/d").unwrap());
+        assert_eq!("\u{e9}t\u{e9}", super::to_string("\u{e9}t\u{e9}").unwrap());
+        assert_eq!("-", super::to_string("-").unwrap());
+        assert_eq!("x", super::to_string(&'x').unwrap());
+        assert_eq!(r#""""#, super::to_string("").unwrap());
+        assert_eq!(r#""true""#, super::to_string("true").unwrap());
+        assert_eq!(r#""null""#, super::to_string("null").unwrap());
+        assert_eq!("trueish", super::to_string("trueish").unwrap());
+        assert_eq!(r#""123""#, super::to_string("123").unwrap());
+        assert_eq!(r#""-1a""#, super::to_string("-1a").unwrap());
+        assert_eq!(r#""0x1f""#, super::to_string("0x1f").unwrap());
+        assert_eq!(r#""foo bar""#, super::to_string("foo bar").unwrap());
+        assert_eq!(r#""a,b""#, super::to_string("a,b").unwrap());
+        assert_eq!(r##""#a""##, super::to_string("#a").unwrap());
+        assert_eq!(r#""~A""#, super::to_string("~A").unwrap());
+        assert_eq!(r#""a\nb""#, super::to_string("a\nb").unwrap());
+        assert_eq!(r#"" ""#, super::to_string(&' ').unwrap());
+
+        let options = super::SerializerOptions {
+            always_quote_strings: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            r#"~Struct {"a" 1,"b" []}"#,
+            super::to_string_with_options(&Struct { a: 1, b: vec![] }, options).unwrap()
+        );
+
+        // Everything reads back as the string it was
+        for s in [
+            "production",
+            "",
+            "true",
+            "false",
+            "null",
+            "123",
+            "-1",
+            "+1",
+            "1.5",
+            "0x1f",
+            "foo bar",
+            "a,b",
+            "[a]",
+            "{",
+            "#",
+            "a#b",
+            "~A",
+            "'a'",
+            "`a",
+            "\t",
+            "\u{e9}",
+        ] {
+            let paml = super::to_string(s).unwrap();
+            assert_eq!(s, crate::from_str::<String>(&paml).unwrap(), "{}", paml);
+            let paml = super::to_string(&vec![s, s]).unwrap();
+            assert_eq!(
+                vec![s, s],
+                crate::from_str::<Vec<String>>(&paml).unwrap(),
+                "{}",
+                paml
+            );
+        }
+    }
+
+    #[derive(Serialize, PartialEq, Eq, PartialOrd, Ord)]
+    struct Id(u32);
+
+    #[derive(Serialize)]
+    enum Color {
+        Red,
+    }
+
+    #[test]
+    fn test_tags() {
+        assert_eq!("~Id 5", super::to_string(&Id(5)).unwrap());
+        assert_eq!("~Red null", super::to_string(&Color::Red).unwrap());
+        let map = BTreeMap::from([(Id(1), Color::Red)]);
+        assert_eq!("{~Id 1 ~Red null}", super::to_string(&map).unwrap());
+        assert_eq!("~Some 5", super::to_string(&Some(5)).unwrap());
+        assert_eq!("~None null", super::to_string(&None::<i32>).unwrap());
+    }
+
+    /// A newtype whose name has a space in it, which comes out as two words
+    struct BadName;
+
+    impl Serialize for BadName {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_newtype_struct("Bad Name", &1)
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Serializer produced invalid output")]
+    fn test_self_check() {
+        let _ = super::to_string(&BadName);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Serializer produced invalid output")]
+    fn test_to_writer_self_check() {
+        let _ = super::to_writer(Vec::new(), &BadName);
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Marker;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    enum Shape {
+        Empty,
+        Circle(f64),
+        Rect { w: f64, h: f64 },
+    }
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Everything {
+        marker: Marker,
+        strings: Vec<String>,
+        chars: Vec<char>,
+        floats: Vec<f64>,
+        ints: (i8, u64, i128),
+        options: Vec<Option<Option<i32>>>,
+        shapes: Vec<Shape>,
+        units: Vec<()>,
+    }
+
+    /// Serialize `val` and read it back as the same type. The self-check only
+    /// makes sure the output parses, so this is what catches output that reads
+    /// back as a different value.
+    fn assert_round_trip<T>(val: &T)
+    where
+        T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug,
+    {
+        let paml = super::to_string(val).unwrap();
+        assert_eq!(val, &crate::from_str::<T>(&paml).unwrap(), "{}", paml);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let strings = [
+            "", "true", "null", "5", "-1", "1e5", "0x10", "~tag", "a b", "\t", "`", "#", "it's",
+            "[", "a,b",
+        ];
+        assert_round_trip(&Everything {
+            marker: Marker,
+            strings: strings.map(String::from).to_vec(),
+            chars: vec!['a', '1', ' ', '"', '\n'],
+            floats: vec![0.0, -0.0, 0.1, 1e300, f64::MIN_POSITIVE],
+            ints: (i8::MIN, u64::MAX, i128::MIN),
+            options: vec![None, Some(None), Some(Some(1))],
+            shapes: vec![
+                Shape::Empty,
+                Shape::Circle(1.5),
+                Shape::Rect { w: 1.0, h: 2.0 },
+            ],
+            units: vec![(), ()],
+        });
+        assert_round_trip(&Marker);
+        assert_round_trip(&Some(Marker));
+        assert_round_trip(&BTreeMap::from([("null".to_string(), Shape::Empty)]));
+    }
+
+    /// Accepts `room` bytes and then fails
+    struct FullWriter {
+        room: usize,
+    }
+
+    impl std::io::Write for FullWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.room == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::WriteZero,
+                    "no room",
+                ));
+            }
+            let len = buf.len().min(self.room);
+            self.room -= len;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_to_writer() {
+        let s = Struct {
+            a: 1,
+            b: vec![2, 3],
+        };
+        let mut output = Vec::new();
+        super::to_writer(&mut output, &s).unwrap();
+        assert_eq!(br#"~Struct {a 1,b [2,3]}"#.as_slice(), output);
+
+        let mut serializer = super::Serializer::new(Vec::new());
+        s.serialize(&mut serializer).unwrap();
+        assert_eq!(output, serializer.into_inner());
+
+        let options = super::SerializerOptions {
+            always_quote_strings: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        super::to_writer_with_options(&mut output, &vec!["a"], options).unwrap();
+        assert_eq!(br#"["a"]"#.as_slice(), output);
+
+        for room in [0, 5, 20] {
+            assert!(matches!(
+                super::to_writer(FullWriter { room }, &s),
+                Err(crate::Error::Io(_))
+            ));
+        }
+    }
+}
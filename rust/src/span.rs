@@ -0,0 +1,388 @@
+use std::fmt;
+
+/// The start and end byte offsets of a piece of the input. `start` must not be
+/// after `end`; the methods here assume it isn't, e.g. [Span::len] panics or
+/// wraps otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// The smallest span covering both spans
+    pub fn merge(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+
+    /// Whether the byte at `pos` is inside this span
+    pub fn contains(self, pos: usize) -> bool {
+        self.start <= pos && pos < self.end
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.start == self.end
+    }
+
+    pub fn len(self) -> usize {
+        self.end - self.start
+    }
+
+    /// Whether the spans share at least one byte
+    pub fn overlaps(self, other: Span) -> bool {
+        !self.is_empty() && !other.is_empty() && self.start < other.end && other.start < self.end
+    }
+
+    /// The part of `source` this span covers, or `None` if the span goes past
+    /// the end of `source` or doesn't start and end on char boundaries
+    pub fn text(self, source: &str) -> Option<&str> {
+        source.get(self.start..self.end)
+    }
+
+    /// The 1-based line and column (in chars) where this span starts.
+    ///
+    /// This scans the source every time, so use a [SourceMap] when resolving
+    /// lots of spans in the same source.
+    pub fn line_col(&self, source: &str) -> (usize, usize) {
+        let offset = floor_char_boundary(source, self.start);
+        let (line, line_start) = line_starts(source)
+            .take_while(|&start| start <= offset)
+            .enumerate()
+            .last()
+            .unwrap_or_default();
+        (line + 1, source[line_start..offset].chars().count() + 1)
+    }
+}
+
+/// A byte offset along with the 1-based line and column (in chars) it's at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Position {
+    pub fn new(source: &str, offset: usize) -> Self {
+        let (line, column) = Span {
+            start: offset,
+            end: offset,
+        }
+        .line_col(source);
+        Position {
+            offset,
+            line,
+            column,
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// Resolves byte offsets in a source to line and column numbers and back,
+/// using an index of where each line starts.
+///
+/// Lines end at `\n`, `\r\n` or a lone `\r`. Columns are counted in chars,
+/// or in UTF-16 code units by the `_utf16` methods, which is what the Language
+/// Server Protocol uses.
+pub struct SourceMap<'a> {
+    source: &'a str,
+    /// Byte offset of the start of every line
+    line_starts: Vec<usize>,
+}
+
+impl<'a> SourceMap<'a> {
+    pub fn new(source: &'a str) -> Self {
+        SourceMap {
+            source,
+            line_starts: line_starts(source).collect(),
+        }
+    }
+
+    /// The 1-based line and column (in chars) where the span starts
+    pub fn line_col(&self, span: Span) -> (usize, usize) {
+        self.offset_line_col(span.start)
+    }
+
+    /// The 1-based line and column (in chars) of a byte offset
+    pub fn offset_line_col(&self, offset: usize) -> (usize, usize) {
+        self.resolve(offset, |_| 1)
+    }
+
+    /// Like [SourceMap::offset_line_col], but the column is in UTF-16 code
+    /// units
+    pub fn offset_line_col_utf16(&self, offset: usize) -> (usize, usize) {
+        self.resolve(offset, char::len_utf16)
+    }
+
+    /// The byte offset of a 1-based line and column (in chars). `None` if the
+    /// line doesn't exist or is too short. The column may point at the line
+    /// break, or just past the end of the last line.
+    pub fn offset(&self, line: usize, column: usize) -> Option<usize> {
+        self.unresolve(line, column, |_| 1)
+    }
+
+    /// Like [SourceMap::offset], but the column is in UTF-16 code units. Also
+    /// `None` if the column is in the middle of a surrogate pair.
+    pub fn offset_utf16(&self, line: usize, column: usize) -> Option<usize> {
+        self.unresolve(line, column, char::len_utf16)
+    }
+
+    /// The text of a 1-based line, without the line break
+    pub fn line_text(&self, line: usize) -> Option<&'a str> {
+        let start = *self.line_starts.get(line.checked_sub(1)?)?;
+        let end = self
+            .line_starts
+            .get(line)
+            .copied()
+            .unwrap_or(self.source.len());
+        let text = &self.source[start..end];
+        let text = text.strip_suffix('\n').unwrap_or(text);
+        Some(text.strip_suffix('\r').unwrap_or(text))
+    }
+
+    /// Find the line and column of `offset`, where `width` says how many
+    /// columns a char takes up
+    fn resolve(&self, offset: usize, width: fn(char) -> usize) -> (usize, usize) {
+        let offset = floor_char_boundary(self.source, offset);
+        let line = self.line_starts.partition_point(|&start| start <= offset);
+        let line_start = self.line_starts[line - 1];
+        let column = self.source[line_start..offset]
+            .chars()
+            .map(width)
+            .sum::<usize>();
+        (line, column + 1)
+    }
+
+    /// The inverse of [SourceMap::resolve]
+    fn unresolve(&self, line: usize, column: usize, width: fn(char) -> usize) -> Option<usize> {
+        let mut offset = *self.line_starts.get(line.checked_sub(1)?)?;
+        // Offsets up to this one (exclusive) are on the line
+        let end = match self.line_starts.get(line) {
+            Some(&next) => next,
+            None => self.source.len() + 1,
+        };
+        let mut col = 1;
+        for c in self.source[offset..end.min(self.source.len())].chars() {
+            if col >= column {
+                break;
+            }
+            col += width(c);
+            offset += c.len_utf8();
+        }
+        (col == column && offset < end).then_some(offset)
+    }
+}
+
+/// The byte offset of the start of every line
+fn line_starts(source: &str) -> impl Iterator<Item = usize> + '_ {
+    let breaks = source.char_indices().filter_map(|(i, c)| match c {
+        '\n' => Some(i + 1),
+        '\r' if !source[i + 1..].starts_with('\n') => Some(i + 1),
+        _ => None,
+    });
+    std::iter::once(0).chain(breaks)
+}
+
+/// Move `offset` back to the nearest char boundary, staying inside `source`
+fn floor_char_boundary(source: &str, offset: usize) -> usize {
+    let mut offset = offset.min(source.len());
+    while !source.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SourceMap, Span};
+    use crate::test_util::Rng;
+
+    fn span(start: usize) -> Span {
+        Span { start, end: start }
+    }
+
+    /// Check that both ways of resolving a span agree
+    fn line_col(source: &str, start: usize) -> (usize, usize) {
+        let res = span(start).line_col(source);
+        assert_eq!(res, SourceMap::new(source).line_col(span(start)));
+        res
+    }
+
+    #[test]
+    fn test_single_line() {
+        assert_eq!((1, 1), line_col("", 0));
+        assert_eq!((1, 1), line_col("{ a b }", 0));
+        assert_eq!((1, 5), line_col("{ a b }", 4));
+        assert_eq!((1, 8), line_col("{ a b }", 7));
+    }
+
+    #[test]
+    fn test_multi_line() {
+        let source = "{\n  a b\n\n  c d\n}";
+        assert_eq!((1, 2), line_col(source, 1));
+        assert_eq!((2, 1), line_col(source, 2));
+        assert_eq!((2, 3), line_col(source, 4));
+        assert_eq!((3, 1), line_col(source, 8));
+        assert_eq!((4, 3), line_col(source, 11));
+        assert_eq!((5, 1), line_col(source, 15));
+    }
+
+    #[test]
+    fn test_crlf() {
+        let source = "a\r\nb\r\nc";
+        assert_eq!((1, 2), line_col(source, 1));
+        assert_eq!((2, 1), line_col(source, 3));
+        assert_eq!((3, 1), line_col(source, 6));
+    }
+
+    #[test]
+    fn test_lone_cr() {
+        let source = "a\rb\r\rc\r\n";
+        assert_eq!((1, 2), line_col(source, 1));
+        assert_eq!((2, 1), line_col(source, 2));
+        assert_eq!((3, 1), line_col(source, 4));
+        assert_eq!((4, 1), line_col(source, 5));
+        assert_eq!((4, 2), line_col(source, 6));
+        assert_eq!((5, 1), line_col(source, 8));
+    }
+
+    #[test]
+    fn test_multibyte() {
+        // 'é' is 2 bytes and '😀' is 4, but each is one column
+        let source = "é😀 x\nü y";
+        assert_eq!((1, 3), line_col(source, 6));
+        assert_eq!((1, 4), line_col(source, 7));
+        assert_eq!((2, 2), line_col(source, 11));
+        // Offsets inside a char resolve to the start of that char
+        assert_eq!((1, 2), line_col(source, 4));
+        // Offsets past the end resolve to the end
+        assert_eq!((2, 4), line_col(source, 100));
+    }
+
+    #[test]
+    fn test_offset() {
+        let map = SourceMap::new("ab\n\u{e9}x\r\ny");
+        assert_eq!(Some(0), map.offset(1, 1));
+        assert_eq!(Some(2), map.offset(1, 3));
+        assert_eq!(Some(3), map.offset(2, 1));
+        assert_eq!(Some(5), map.offset(2, 2));
+        assert_eq!(Some(7), map.offset(2, 4));
+        assert_eq!(Some(8), map.offset(3, 1));
+        // Just past the end of the last line
+        assert_eq!(Some(9), map.offset(3, 2));
+        assert_eq!(None, map.offset(1, 4));
+        assert_eq!(None, map.offset(2, 5));
+        assert_eq!(None, map.offset(3, 3));
+        assert_eq!(None, map.offset(4, 1));
+        assert_eq!(None, map.offset(0, 1));
+        assert_eq!(None, map.offset(1, 0));
+    }
+
+    #[test]
+    fn test_utf16() {
+        // '😀' is 2 UTF-16 code units, 'é' is 1
+        let map = SourceMap::new("\u{1F600}\u{e9}x\n\u{1F600}");
+        assert_eq!((1, 3), map.offset_line_col_utf16(4));
+        assert_eq!((1, 4), map.offset_line_col_utf16(6));
+        assert_eq!((1, 5), map.offset_line_col_utf16(7));
+        assert_eq!((2, 3), map.offset_line_col_utf16(12));
+        assert_eq!(Some(6), map.offset_utf16(1, 4));
+        assert_eq!(Some(12), map.offset_utf16(2, 3));
+        // The middle of a surrogate pair
+        assert_eq!(None, map.offset_utf16(1, 2));
+    }
+
+    #[test]
+    fn test_line_text() {
+        let map = SourceMap::new("a\r\nbc\rd\n\ne");
+        assert_eq!(Some("a"), map.line_text(1));
+        assert_eq!(Some("bc"), map.line_text(2));
+        assert_eq!(Some("d"), map.line_text(3));
+        assert_eq!(Some(""), map.line_text(4));
+        assert_eq!(Some("e"), map.line_text(5));
+        assert_eq!(None, map.line_text(6));
+        assert_eq!(None, map.line_text(0));
+    }
+
+    /// Going from an offset to a line and column and back gives the same
+    /// offset, for every char boundary in some pseudorandom documents
+    #[test]
+    fn test_round_trip() {
+        const CHARS: &[char] = &['a', ' ', '\n', '\r', '\u{e9}', '\u{4e16}', '\u{1F600}'];
+        let mut rng = Rng(0xda94_2042_e4dd_58b5);
+        for len in 0..200 {
+            let source: String = (0..len)
+                .map(|_| CHARS[rng.next_u64() as usize % CHARS.len()])
+                .collect();
+            let map = SourceMap::new(&source);
+            for offset in (0..=source.len()).filter(|&o| source.is_char_boundary(o)) {
+                let (line, column) = line_col(&source, offset);
+                assert_eq!(Some(offset), map.offset(line, column), "{:?}", source);
+                let (line, column) = map.offset_line_col_utf16(offset);
+                assert_eq!(Some(offset), map.offset_utf16(line, column), "{:?}", source);
+            }
+        }
+    }
+
+    fn s(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    #[test]
+    fn test_merge() {
+        assert_eq!(s(1, 8), s(1, 3).merge(s(5, 8)));
+        assert_eq!(s(1, 8), s(5, 8).merge(s(1, 3)));
+        assert_eq!(s(1, 8), s(1, 8).merge(s(2, 3)));
+        assert_eq!(s(2, 5), s(2, 2).merge(s(5, 5)));
+    }
+
+    #[test]
+    fn test_contains() {
+        assert!(s(2, 4).contains(2));
+        assert!(s(2, 4).contains(3));
+        assert!(!s(2, 4).contains(4));
+        assert!(!s(2, 4).contains(1));
+        assert!(!s(2, 2).contains(2));
+    }
+
+    #[test]
+    fn test_len() {
+        assert_eq!(3, s(2, 5).len());
+        assert!(!s(2, 5).is_empty());
+        assert_eq!(0, s(4, 4).len());
+        assert!(s(4, 4).is_empty());
+    }
+
+    #[test]
+    fn test_overlaps() {
+        assert!(s(1, 4).overlaps(s(3, 6)));
+        assert!(s(3, 6).overlaps(s(1, 4)));
+        assert!(s(1, 6).overlaps(s(2, 3)));
+        // Adjacent spans don't share a byte
+        assert!(!s(1, 3).overlaps(s(3, 5)));
+        assert!(!s(3, 5).overlaps(s(1, 3)));
+        // Neither do empty ones
+        assert!(!s(2, 2).overlaps(s(1, 3)));
+        assert!(!s(2, 2).overlaps(s(2, 2)));
+    }
+
+    #[test]
+    fn test_text() {
+        let source = "{ key \u{e9}t\u{e9} }";
+        assert_eq!(Some("key"), s(2, 5).text(source));
+        assert_eq!(Some("\u{e9}t\u{e9}"), s(6, 11).text(source));
+        assert_eq!(Some(""), s(3, 3).text(source));
+        // Inside the first é
+        assert_eq!(None, s(7, 9).text(source));
+        assert_eq!(None, s(0, source.len() + 1).text(source));
+        assert_eq!(None, s(5, 3).text(source));
+    }
+}